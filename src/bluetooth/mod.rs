@@ -1 +0,0 @@
-pub mod discovery;
\ No newline at end of file
@@ -1,11 +1,3 @@
-use rand::RngCore;
-
-pub fn generate_encryption_key() -> Vec<u8> {
-    let mut key = [0u8; 32];
-    rand::thread_rng().fill_bytes(&mut key);
-    key.to_vec()
-}
-
 pub fn key_to_hex_string(key: &[u8]) -> String {
     key.iter().map(|b| format!("{:02x}", b)).collect()
 }
@@ -0,0 +1,102 @@
+//! Ephemeral X25519 key exchange carried over the GATT link.
+//!
+//! Both peers send a fresh public key, derive the session key with
+//! HKDF-SHA256 over the shared secret and both public keys, and show a
+//! six-digit short authentication string (SAS) taken from the same
+//! derivation. If the codes match on both screens, nobody sat in the middle.
+
+use hkdf::Hkdf;
+use rand::rngs::OsRng;
+use sha2::Sha256;
+use x25519_dalek::{EphemeralSecret, PublicKey};
+
+const HKDF_SALT: &[u8] = b"fling-kx-v1";
+
+#[derive(Clone, Copy, Debug)]
+pub enum Role {
+    Sender,
+    Receiver,
+}
+
+/// The receiver's answer once the user has compared the codes and seen the
+/// offer, written as one byte to the sender's confirm characteristic.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum Verdict {
+    CodeMismatch,
+    Accept,
+    Decline,
+}
+
+impl Verdict {
+    pub fn to_byte(self) -> u8 {
+        match self {
+            Verdict::CodeMismatch => 0,
+            Verdict::Accept => 1,
+            Verdict::Decline => 2,
+        }
+    }
+
+    pub fn from_byte(value: &[u8]) -> Option<Self> {
+        match value {
+            [0] => Some(Verdict::CodeMismatch),
+            [1] => Some(Verdict::Accept),
+            [2] => Some(Verdict::Decline),
+            _ => None,
+        }
+    }
+}
+
+pub struct Handshake {
+    role: Role,
+    secret: EphemeralSecret,
+    public: PublicKey,
+}
+
+/// Result of a completed handshake: the tunnel key and the code users compare.
+pub struct SessionKeys {
+    pub key: Vec<u8>,
+    pub sas: String,
+}
+
+impl Handshake {
+    pub fn new(role: Role) -> Self {
+        let secret = EphemeralSecret::random_from_rng(OsRng);
+        let public = PublicKey::from(&secret);
+        Self { role, secret, public }
+    }
+
+    pub fn public_key(&self) -> Vec<u8> {
+        self.public.as_bytes().to_vec()
+    }
+
+    pub fn finish(self, peer_public: &[u8]) -> Result<SessionKeys, String> {
+        let peer: [u8; 32] = peer_public
+            .try_into()
+            .map_err(|_| format!("Invalid peer public key length: {}", peer_public.len()))?;
+        let peer = PublicKey::from(peer);
+
+        let shared = self.secret.diffie_hellman(&peer);
+        if !shared.was_contributory() {
+            return Err("Peer sent a low-order public key".into());
+        }
+
+        let (sender_pub, receiver_pub) = match self.role {
+            Role::Sender => (self.public, peer),
+            Role::Receiver => (peer, self.public),
+        };
+        let mut info = Vec::with_capacity(64);
+        info.extend_from_slice(sender_pub.as_bytes());
+        info.extend_from_slice(receiver_pub.as_bytes());
+
+        let mut okm = [0u8; 36];
+        Hkdf::<Sha256>::new(Some(HKDF_SALT), shared.as_bytes())
+            .expand(&info, &mut okm)
+            .map_err(|_| "Key derivation failed".to_string())?;
+
+        let code = u32::from_be_bytes([okm[32], okm[33], okm[34], okm[35]]) % 1_000_000;
+        Ok(SessionKeys {
+            key: okm[..32].to_vec(),
+            sas: format!("{:03} {:03}", code / 1000, code % 1000),
+        })
+    }
+}
@@ -1 +1,5 @@
-pub mod crypto;
\ No newline at end of file
+#[allow(clippy::module_inception)]
+pub mod crypto;
+pub mod handshake;
+pub mod proof;
+pub mod stream;
@@ -0,0 +1,133 @@
+//! Proof of the session key at the start of every transfer connection.
+//!
+//! Anything on the hotspot or the LAN can connect to the sender's port, and
+//! anything can answer at an address the receiver was told to try. So before
+//! the session starts, each side sends a fresh random challenge and answers
+//! the other's with HMAC-SHA256 under a key derived from the session key.
+//! The answer covers the role giving it, so a peer cannot reflect a
+//! challenge back and pass off the answer as its own.
+//!
+//! Sender: `MAGIC || challenge`. Receiver: `challenge || answer`. Sender:
+//! `answer`.
+//!
+//! The stream ciphers start their frame counters at zero on every
+//! connection, so they must never run under the session key itself, or a
+//! resumed transfer would reuse nonces and a recorded connection could be
+//! replayed into the next. Both sides instead key them with the connection
+//! key that `verify_receiver` and `verify_sender` return, derived from the
+//! session key and both of this connection's challenges.
+
+use hkdf::Hkdf;
+use hmac::{Hmac, Mac};
+use rand::RngCore;
+use sha2::Sha256;
+use std::io::{Error, ErrorKind, Result};
+use std::time::Duration;
+use tokio::io::{AsyncRead, AsyncReadExt, AsyncWrite, AsyncWriteExt};
+use tokio::time::timeout;
+
+use crate::crypto::handshake::Role;
+
+const MAGIC: &[u8; 4] = b"FLPF";
+const PROOF_INFO: &[u8] = b"fling-tcp-proof-v1";
+const CONNECTION_INFO: &[u8] = b"fling-tcp-connection-v1";
+const CHALLENGE_LEN: usize = 16;
+const ANSWER_LEN: usize = 32;
+/// How long the peer gets to answer, so a silent connection cannot hold
+/// up the one that matters.
+const PROOF_TIMEOUT: Duration = Duration::from_secs(5);
+
+fn answer(key: &[u8], role: Role, challenge: &[u8]) -> Result<Hmac<Sha256>> {
+    let mut proof_key = [0u8; 32];
+    Hkdf::<Sha256>::new(None, key)
+        .expand(PROOF_INFO, &mut proof_key)
+        .map_err(|_| Error::other("Key derivation failed"))?;
+    let mut mac = Hmac::<Sha256>::new_from_slice(&proof_key)
+        .map_err(|_| Error::other("Key derivation failed"))?;
+    mac.update(match role {
+        Role::Sender => b"sender",
+        Role::Receiver => b"receiver",
+    });
+    mac.update(challenge);
+    Ok(mac)
+}
+
+fn check(key: &[u8], role: Role, challenge: &[u8], given: &[u8]) -> Result<()> {
+    answer(key, role, challenge)?
+        .verify_slice(given)
+        .map_err(|_| Error::new(ErrorKind::InvalidData, "peer does not hold the session key"))
+}
+
+/// The key for this connection's streams, fresh because the challenges are.
+fn connection_key(key: &[u8], sender: &[u8], receiver: &[u8]) -> Result<Vec<u8>> {
+    let mut connection_key = vec![0u8; 32];
+    Hkdf::<Sha256>::new(Some(&[sender, receiver].concat()), key)
+        .expand(CONNECTION_INFO, &mut connection_key)
+        .map_err(|_| Error::other("Key derivation failed"))?;
+    Ok(connection_key)
+}
+
+fn challenge() -> [u8; CHALLENGE_LEN] {
+    let mut challenge = [0u8; CHALLENGE_LEN];
+    rand::thread_rng().fill_bytes(&mut challenge);
+    challenge
+}
+
+async fn within<T>(proof: impl Future<Output = Result<T>>) -> Result<T> {
+    timeout(PROOF_TIMEOUT, proof)
+        .await
+        .map_err(|_| Error::new(ErrorKind::TimedOut, "peer did not answer the key proof"))?
+}
+
+/// Sender: checks that the receiver on `stream` holds `key`, and shows it
+/// that the sender does too. Returns the key for this connection's streams.
+pub async fn verify_receiver<S>(stream: &mut S, key: &[u8]) -> Result<Vec<u8>>
+where
+    S: AsyncRead + AsyncWrite + Unpin,
+{
+    within(async {
+        let ours = challenge();
+        stream.write_all(MAGIC).await?;
+        stream.write_all(&ours).await?;
+        stream.flush().await?;
+
+        let mut reply = [0u8; CHALLENGE_LEN + ANSWER_LEN];
+        stream.read_exact(&mut reply).await?;
+        let (theirs, given) = reply.split_at(CHALLENGE_LEN);
+        check(key, Role::Receiver, &ours, given)?;
+
+        let proof = answer(key, Role::Sender, theirs)?.finalize().into_bytes();
+        stream.write_all(&proof).await?;
+        stream.flush().await?;
+        connection_key(key, &ours, theirs)
+    })
+    .await
+}
+
+/// Receiver: checks that the sender on `stream` holds `key`, and shows it
+/// that the receiver does too. Returns the key for this connection's streams.
+pub async fn verify_sender<S>(stream: &mut S, key: &[u8]) -> Result<Vec<u8>>
+where
+    S: AsyncRead + AsyncWrite + Unpin,
+{
+    within(async {
+        let mut hello = [0u8; MAGIC.len() + CHALLENGE_LEN];
+        stream.read_exact(&mut hello).await?;
+        let (magic, theirs) = hello.split_at(MAGIC.len());
+        if magic != MAGIC {
+            return Err(Error::new(ErrorKind::InvalidData, "not a fling sender"));
+        }
+
+        let ours = challenge();
+        let proof = answer(key, Role::Receiver, theirs)?.finalize().into_bytes();
+        stream.write_all(&ours).await?;
+        stream.write_all(&proof).await?;
+        stream.flush().await?;
+
+        let mut given = [0u8; ANSWER_LEN];
+        stream.read_exact(&mut given).await?;
+        check(key, Role::Sender, &ours, &given)?;
+        connection_key(key, theirs, &ours)
+    })
+    .await
+}
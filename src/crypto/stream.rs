@@ -0,0 +1,190 @@
+//! AES-256-GCM framed stream used inside the Wi-Fi tunnel.
+//!
+//! Each frame on the wire is `[u32 BE length][ciphertext || tag]`. The length
+//! prefix is authenticated as associated data, and every frame is sealed with a
+//! nonce built from the stream direction and a running frame counter. A frame
+//! that is modified, dropped, reordered or replayed therefore fails to decrypt.
+//! An empty frame marks the authenticated end of the stream.
+//!
+//! The counter starts over on every connection, so each connection needs a
+//! key of its own: the one `crypto::proof` derives, never the session key.
+
+use aes_gcm::{
+    Aes256Gcm, Nonce,
+    aead::{Aead, KeyInit, Payload},
+};
+use std::io::{Error, ErrorKind, Result};
+use tokio::io::{AsyncRead, AsyncReadExt, AsyncWrite, AsyncWriteExt};
+
+/// Largest ciphertext (including the 16-byte tag) a reader will accept.
+pub const MAX_FRAME_LEN: usize = 4 * 1024 * 1024;
+const TAG_LEN: usize = 16;
+
+/// Which way a frame travels. Each direction has its own nonce space so the
+/// two peers never seal different frames under the same nonce.
+#[derive(Clone, Copy, Debug)]
+pub enum Direction {
+    SenderToReceiver,
+    ReceiverToSender,
+}
+
+impl Direction {
+    fn tag(self) -> [u8; 4] {
+        match self {
+            Direction::SenderToReceiver => *b"fl>r",
+            Direction::ReceiverToSender => *b"fl<s",
+        }
+    }
+}
+
+struct FrameCipher {
+    cipher: Aes256Gcm,
+    direction: Direction,
+    counter: u64,
+}
+
+impl FrameCipher {
+    fn new(key: &[u8], direction: Direction) -> Result<Self> {
+        let cipher = Aes256Gcm::new_from_slice(key).map_err(|_| {
+            Error::new(
+                ErrorKind::InvalidInput,
+                format!("Invalid session key length: {} bytes (expected 32)", key.len()),
+            )
+        })?;
+        Ok(Self { cipher, direction, counter: 0 })
+    }
+
+    fn next_nonce(&mut self) -> Result<[u8; 12]> {
+        let mut nonce = [0u8; 12];
+        nonce[..4].copy_from_slice(&self.direction.tag());
+        nonce[4..].copy_from_slice(&self.counter.to_be_bytes());
+        self.counter = self
+            .counter
+            .checked_add(1)
+            .ok_or_else(|| Error::other("Frame counter exhausted"))?;
+        Ok(nonce)
+    }
+}
+
+/// Seals plaintext chunks into authenticated frames on an `AsyncWrite`.
+pub struct SecureWriter<W> {
+    inner: W,
+    cipher: FrameCipher,
+}
+
+impl<W: AsyncWrite + Unpin> SecureWriter<W> {
+    pub fn new(inner: W, key: &[u8], direction: Direction) -> Result<Self> {
+        Ok(Self { inner, cipher: FrameCipher::new(key, direction)? })
+    }
+
+    pub async fn write_frame(&mut self, plaintext: &[u8]) -> Result<()> {
+        let sealed_len = plaintext.len() + TAG_LEN;
+        if sealed_len > MAX_FRAME_LEN {
+            return Err(Error::new(
+                ErrorKind::InvalidInput,
+                format!("Frame too large: {} bytes", plaintext.len()),
+            ));
+        }
+        let len_prefix = (sealed_len as u32).to_be_bytes();
+        let nonce = self.cipher.next_nonce()?;
+        let sealed = self
+            .cipher
+            .cipher
+            .encrypt(Nonce::from_slice(&nonce), Payload { msg: plaintext, aad: &len_prefix })
+            .map_err(|_| Error::other("Encryption failed"))?;
+
+        self.inner.write_all(&len_prefix).await?;
+        self.inner.write_all(&sealed).await
+    }
+
+    /// Writes the authenticated end-of-stream marker and flushes the socket.
+    pub async fn finish(&mut self) -> Result<()> {
+        self.write_frame(&[]).await?;
+        self.inner.flush().await
+    }
+}
+
+/// Opens authenticated frames from an `AsyncRead`.
+pub struct SecureReader<R> {
+    inner: R,
+    cipher: FrameCipher,
+}
+
+impl<R: AsyncRead + Unpin> SecureReader<R> {
+    pub fn new(inner: R, key: &[u8], direction: Direction) -> Result<Self> {
+        Ok(Self { inner, cipher: FrameCipher::new(key, direction)? })
+    }
+
+    /// Returns the next plaintext chunk, or `None` once the peer has sent the
+    /// end-of-stream marker. A connection that closes before that marker is an
+    /// `UnexpectedEof` error; a frame that fails authentication is
+    /// `InvalidData`.
+    pub async fn read_frame(&mut self) -> Result<Option<Vec<u8>>> {
+        let mut len_prefix = [0u8; 4];
+        self.inner.read_exact(&mut len_prefix).await.map_err(|e| {
+            if e.kind() == ErrorKind::UnexpectedEof {
+                Error::new(e.kind(), "Connection closed before the end of the encrypted stream")
+            } else {
+                e
+            }
+        })?;
+
+        let sealed_len = u32::from_be_bytes(len_prefix) as usize;
+        if !(TAG_LEN..=MAX_FRAME_LEN).contains(&sealed_len) {
+            return Err(Error::new(
+                ErrorKind::InvalidData,
+                format!("Rejected frame with invalid length {}", sealed_len),
+            ));
+        }
+
+        let mut sealed = vec![0u8; sealed_len];
+        self.inner.read_exact(&mut sealed).await?;
+
+        let nonce = self.cipher.next_nonce()?;
+        let plaintext = self
+            .cipher
+            .cipher
+            .decrypt(Nonce::from_slice(&nonce), Payload { msg: &sealed, aad: &len_prefix })
+            .map_err(|_| {
+                Error::new(
+                    ErrorKind::InvalidData,
+                    format!(
+                        "Frame {} failed authentication (tampered, replayed or wrong key); aborting",
+                        self.cipher.counter - 1
+                    ),
+                )
+            })?;
+
+        if plaintext.is_empty() {
+            Ok(None)
+        } else {
+            Ok(Some(plaintext))
+        }
+    }
+}
+
+/// Nonce prefix for standalone messages, kept apart from both frame
+/// directions.
+const MESSAGE_TAG: [u8; 4] = *b"fl-m";
+
+/// Seals one standalone message outside the framed stream, such as the
+/// offer served over GATT. Every call uses the same nonce, so seal at most
+/// one message per session key.
+pub fn seal_message(key: &[u8], plaintext: &[u8]) -> Result<Vec<u8>> {
+    let cipher = FrameCipher::new(key, Direction::SenderToReceiver)?.cipher;
+    let mut nonce = [0u8; 12];
+    nonce[..4].copy_from_slice(&MESSAGE_TAG);
+    cipher
+        .encrypt(Nonce::from_slice(&nonce), plaintext)
+        .map_err(|_| Error::other("Encryption failed"))
+}
+
+/// Opens a message sealed by `seal_message`.
+pub fn open_message(key: &[u8], sealed: &[u8]) -> Result<Vec<u8>> {
+    let cipher = FrameCipher::new(key, Direction::SenderToReceiver)?.cipher;
+    let mut nonce = [0u8; 12];
+    nonce[..4].copy_from_slice(&MESSAGE_TAG);
+    cipher
+        .decrypt(Nonce::from_slice(&nonce), sealed)
+        .map_err(|_| Error::new(ErrorKind::InvalidData, "Message failed authentication"))
+}
@@ -1,30 +1,62 @@
-use crate::{bluetooth, crypto, tunnel};
+use std::net::SocketAddr;
+use std::time::Duration;
+
+use crate::crypto;
+use crate::crypto::handshake::Verdict;
+use crate::platform::{
+    Backends, Discovery, Interaction, KeyExchange, LinkProvider, Pairing, Transport,
+};
+use crate::tunnel::transfer::{Destination, TransferError};
+
+/// How many times a dropped link may send the FSM back to `JoiningNetwork`.
+const MAX_RESUMES: u32 = 5;
+/// How long the receiver tries the sender's LAN addresses.
+const LAN_WAIT: Duration = Duration::from_secs(5);
+/// How long the receiver tries to reach the sender once on its hotspot.
+const CONNECT_WAIT: Duration = Duration::from_secs(20);
 
 #[derive(Debug)]
-pub enum ReceiverState {
+pub enum ReceiverState<P> {
     Listening,
-    Connecting(Vec<u8>),
-    JoiningNetwork(String, String),
-    Receiving,
+    Verifying(P),
+    Connecting(Vec<SocketAddr>, Vec<u8>),
+    ReceivingOverLan(Vec<SocketAddr>, String, String, Vec<u8>),
+    JoiningNetwork(String, String, Vec<u8>),
+    Receiving(SocketAddr, String, String, Vec<u8>),
     ReceiveSuccess,
     ReceiveFailed,
+    Declined,
     ConnectionFailed,
 }
 
-pub async fn start_receiver_fsm() -> ReceiverState {
+pub async fn start_receiver_fsm<D, K, L, T, U>(
+    backends: &Backends<D, K, L, T, U>,
+    destination: Destination,
+) -> ReceiverState<K::Pairing>
+where
+    D: Discovery,
+    K: KeyExchange,
+    L: LinkProvider,
+    T: Transport,
+    U: Interaction,
+{
     use ReceiverState::*;
 
     let mut state = Listening;
+    let mut resumes = 0;
+    // Only a network fling joined needs restoring afterwards.
+    let mut joined = false;
+    // The port the sender advertised, for when it is reached on its hotspot.
+    let mut port = 0;
 
-    loop {
+    let outcome = loop {
         state = match state {
             Listening => {
-                println!("[Listening] Waiting for Bluetooth connection...");
-                match bluetooth::discovery::receive_fling_key().await {
-                    Ok(key) => {
-                        let key_str = String::from_utf8_lossy(&key);
-                        println!("[Listening] Connected to sender. Received Key: {}", key_str);
-                        Connecting(key)
+                eprintln!("[Listening] Waiting for Bluetooth connection...");
+                match backends.key_exchange.accept().await {
+                    Ok(pairing) => {
+                        eprintln!("[Listening] Connected to sender. Key exchange complete.");
+                        Verifying(pairing)
                     },
                     Err(e) => {
                         eprintln!("[Listening] Failed to wait for connection: {}", e);
@@ -34,34 +66,89 @@ pub async fn start_receiver_fsm() -> ReceiverState {
                 
             }
 
-            Connecting(key) => {
-                #[allow(deprecated)]
-                let hostname = get_hostname();
-                let mac = bluetooth::discovery::get_bluetooth_mac().unwrap();
+            Verifying(pairing) => {
+                let verdict = if !backends.ui.codes_match(pairing.sas()).await {
+                    Verdict::CodeMismatch
+                } else if backends.ui.accept_offer(pairing.offer()).await {
+                    Verdict::Accept
+                } else {
+                    Verdict::Decline
+                };
+
+                let lan = pairing.offer().lan.clone();
+                port = pairing.offer().port;
+                match pairing.respond(verdict).await {
+                    Ok(key) => Connecting(lan, key),
+                    Err(_) if verdict == Verdict::Decline => Declined,
+                    Err(e) => {
+                        eprintln!("[Verifying] Pairing aborted: {}", e);
+                        ConnectionFailed
+                    }
+                }
+            }
+
+            Connecting(lan, key) => {
+                let hostname = backends.discovery.local_name();
+                let Some(mac) = backends.discovery.local_address() else {
+                    eprintln!("[Connecting] Cannot read this machine's Bluetooth address");
+                    break ConnectionFailed;
+                };
                 let mac_fragment = &mac.replace(":", "").to_lowercase();
                 let suffix = &mac_fragment[mac_fragment.len()-4..];
                 let password = crypto::crypto::generate_network_password(&hostname, &key);              
                 let ssid = format!("fling-{}-{}-{}", hostname, suffix, &password[password.len()-2..]);
-                JoiningNetwork(ssid, password)
+                if lan.is_empty() {
+                    JoiningNetwork(ssid, password, key)
+                } else {
+                    ReceivingOverLan(lan, ssid, password, key)
+                }
             }
 
-            JoiningNetwork(ssid, password) => {
-                println!("[JoiningNetwork] Joining SSID {}...", ssid);
-                if tunnel::connection::join_wifi_direct_network(&ssid, &password) == true {
-                        Receiving
-                    }else {
+            ReceivingOverLan(lan, ssid, password, key) => {
+                eprintln!("[LAN] Trying the sender on the local network...");
+                match backends.transport.receive(&lan, LAN_WAIT, &destination, &key).await {
+                    Ok(_) => {
+                        eprintln!("[LAN] File transfer complete!");
+                        ReceiveSuccess
+                    }
+                    Err(TransferError::Interrupted(e)) => {
+                        eprintln!("[LAN] Not reachable ({}). Falling back to the hotspot.", e);
+                        JoiningNetwork(ssid, password, key)
+                    }
+                    Err(e) => {
+                        eprintln!("[LAN] Transfer failed: {}", e);
+                        ReceiveFailed
+                    }
+                }
+            }
+
+            JoiningNetwork(ssid, password, key) => {
+                eprintln!("[JoiningNetwork] Joining SSID {}...", ssid);
+                joined = true;
+                match backends.link.join(&ssid, &password).await {
+                    Ok(sender) => Receiving(SocketAddr::new(sender, port), ssid, password, key),
+                    Err(e) => {
+                        eprintln!("[JoiningNetwork] {}", e);
                         ConnectionFailed
                     }
                 }
+            }
 
-            Receiving => {
-                println!("[Receiving] Awaiting file over socket...");
-                let save_path = "Rec_Folder";
-                match tunnel::transfer::receive_file(save_path).await {
+            Receiving(sender, ssid, password, key) => {
+                eprintln!("[Receiving] Awaiting encrypted file over socket...");
+                match backends.transport.receive(&[sender], CONNECT_WAIT, &destination, &key).await {
                     Ok(_) => {
-                        println!("[Receiving] File transfer complete!");
+                        eprintln!("[Receiving] File transfer complete!");
                         ReceiveSuccess
                     },
+                    Err(TransferError::Interrupted(e)) if resumes < MAX_RESUMES => {
+                        resumes += 1;
+                        eprintln!(
+                            "[Receiving] Link dropped ({}). Rejoining to resume ({}/{})...",
+                            e, resumes, MAX_RESUMES
+                        );
+                        JoiningNetwork(ssid, password, key)
+                    },
                     Err(e) => {
                         eprintln!("[Receiving] Transfer failed: {}", e);
                         ReceiveFailed
@@ -76,34 +163,18 @@ pub async fn start_receiver_fsm() -> ReceiverState {
             ReceiveFailed => {
                 break ReceiveFailed
             },
+            Declined => {
+                eprintln!("[Declined] Transfer declined.");
+                break Declined
+            },
             ConnectionFailed => {
                 break ConnectionFailed
             },
         };
-    }
-}
-
-#[cfg(target_os = "macos")]
-fn get_hostname() -> String {
-    use std::process::Command;
-
-    let hostname_op = Command::new("scutil")
-        .args(&["--get", "ComputerName"])
-        .output()
-        .expect("Failed to fetch hostname");
-
-    let name = if hostname_op.status.success() {
-        let name = String::from_utf8_lossy(&hostname_op.stdout).trim().to_string();
-        name
-    }else {
-        let err_m = String::from_utf8_lossy(&hostname_op.stderr).trim().to_string();
-        eprintln!("Failed to get computer name: {}", err_m);
-        err_m
     };
-    name
-}
 
-#[cfg(target_os = "linux")]
-fn get_hostname() -> String {
-    whoami::fallible::hostname().unwrap_or_else(|_| "unknown".to_string())
+    if joined {
+        backends.link.teardown().await;
+    }
+    outcome
 }
@@ -1,106 +1,190 @@
-#![cfg(target_os="linux")]
-use dialoguer::{Select, theme::ColorfulTheme};
 use std::time::Duration;
+use tokio::sync::mpsc;
+use tokio::time::timeout;
 
-use crate::bluetooth;
 use crate::crypto;
-use crate::tunnel;
+use crate::platform::{
+    Backends, DeviceInfo, Discovery, Interaction, KeyExchange, LinkProvider, Listening, Sighting,
+    Target, Transport,
+};
+use crate::tunnel::transfer::{Payload, TransferError};
+
+/// How many times a dropped link may send the FSM back to `WaitingForJoin`.
+const MAX_RESUMES: u32 = 5;
+/// How long a receiver on a shared network gets to connect and prove the
+/// session key before the sender falls back to its hotspot.
+const LAN_WAIT: Duration = Duration::from_secs(10);
+/// How long a receiver gets to join the hotspot, connect and prove the key.
+const ACCEPT_WAIT: Duration = Duration::from_secs(60);
+/// How long the scan goes on for a target given up front.
+const TARGET_WAIT: Duration = Duration::from_secs(30);
+
 #[derive(Debug)]
 pub enum SenderState {
     Scanning,
-    Connecting(bluetooth::discovery::DeviceInfo),
-    ServingGatt(bluetooth::discovery::DeviceInfo),
-    StartingHotspot(bluetooth::discovery::DeviceInfo, String),
-    WaitingForJoin(bluetooth::discovery::DeviceInfo),
-    Sending,
+    Connecting(DeviceInfo),
+    ServingGatt(DeviceInfo),
+    WaitingOnLan(DeviceInfo, String, Vec<u8>),
+    SendingOverLan(DeviceInfo, String, Vec<u8>),
+    StartingHotspot(DeviceInfo, String, Vec<u8>),
+    WaitingForJoin(DeviceInfo, Vec<u8>),
+    Sending(DeviceInfo, Vec<u8>),
     SendSuccess,
     SendFailed,
+    Declined,
     NoDevicesFound,
     ConnectionFailed,
 }
 
-pub async fn start_sender_fsm(filepath: &str) -> SenderState {
+pub async fn start_sender_fsm<D, K, L, T, U>(
+    backends: &Backends<D, K, L, T, U>,
+    paths: &[String],
+    name: Option<&str>,
+    target: &Target,
+) -> SenderState
+where
+    D: Discovery,
+    K: KeyExchange,
+    L: LinkProvider,
+    T: Transport,
+    U: Interaction,
+{
     use SenderState::*;
-    println!("[Scanning] Initializing Bluetooth...");
 
-    // Initialize AdapterController
-    let adapter = match bluetooth::discovery::AdapterController::initialize().await {
-        Ok(controller) => controller,
+    // Hash everything up front so a missing file fails before any radio work,
+    // and so resumed sessions reuse the same manifest.
+    let mut payload = match Payload::from_args(paths, name) {
+        Ok(payload) => payload,
         Err(e) => {
-            eprintln!("[Scanning] Failed to initialize Bluetooth: {}", e);
-            return NoDevicesFound;
+            eprintln!("[Scanning] Cannot prepare files: {}", e);
+            return SendFailed;
         }
     };
+    let mut resumes = 0;
+    // Where this machine listens, advertised in the offer.
+    let mut listening = Listening::default();
+    // Only a hotspot fling brought up needs tearing down.
+    let mut hosting = false;
+
     let mut state = Scanning;
     loop {
         state = match state {
             Scanning => {
-                println!("[Scanning] Searching for nearby receivers...");
-                let devices = match adapter.scan_devices(Duration::from_secs(5)).await {
-                    Ok(devices) => devices,
-                    Err(e) => {
-                        eprintln!("[Scanning] Scan failed: {}", e);
-                        return NoDevicesFound;
+                eprintln!("[Scanning] Searching for nearby receivers...");
+                // The picker shows receivers as the scan turns them up; the
+                // scan goes on until the user has decided.
+                let (found, devices) = mpsc::unbounded_channel();
+                let picking = async {
+                    match target {
+                        Target::Ask => backends.ui.choose_device(devices).await,
+                        target => first_match(devices, target).await,
+                    }
+                };
+                let scanning = backends.discovery.scan(found);
+                tokio::pin!(picking, scanning);
+                let chosen = tokio::select! {
+                    chosen = &mut picking => chosen,
+                    scanned = &mut scanning => {
+                        if let Err(e) = scanned {
+                            eprintln!("[Scanning] Scan failed: {}", e);
+                        }
+                        picking.await
                     }
                 };
 
-                if devices.is_empty() {
-                    println!("[Scanning] No receivers found.");
+                let Some(chosen) = chosen else {
+                    eprintln!("[Scanning] No device selected.");
                     return NoDevicesFound;
-                }
-
-                println!("[Scanning] Device(s) found! Selecting device...");
-                let index = Select::with_theme(&ColorfulTheme::default())
-                    .with_prompt("Select a device to connect to")
-                    .items(&devices)
-                    .default(0)
-                    .interact()
-                    .unwrap_or(0);
-
-                let chosen = devices[index].clone();
-                println!("[Scanning] Selected device: {}", chosen);
+                };
+                eprintln!("[Scanning] Selected device: {}", chosen);
 
                 Connecting(chosen)
             }
 
-            Connecting(device_info) => {
-                ServingGatt(device_info)
-            }
+            Connecting(device_info) => match backends.transport.listen().await {
+                Ok(endpoints) => {
+                    listening = endpoints;
+                    ServingGatt(device_info)
+                }
+                Err(e) => {
+                    eprintln!("[Connecting] {}", e);
+                    ConnectionFailed
+                }
+            },
             ServingGatt(device_info) => {
-                println!("[GATT] Starting GATT server for key exchange...");
+                eprintln!("[GATT] Starting GATT server for key exchange...");
 
-                let device_address = match device_info.address.parse::<bluer::Address>() {
-                    Ok(addr) => addr,
+                let mut offer = payload.offer(&backends.discovery.local_name());
+                offer.lan = listening.lan.clone();
+                offer.port = listening.port;
+                match backends.key_exchange.offer(&device_info, &offer).await {
+                    Ok(Some(crypto_key)) => {
+                        eprintln!("[GATT] Receiver confirmed the verification code.");
+                        let net_pass =
+                            crypto::crypto::generate_network_password(&device_info.name, &crypto_key);
+                        if listening.lan.is_empty() {
+                            StartingHotspot(device_info, net_pass, crypto_key)
+                        } else {
+                            WaitingOnLan(device_info, net_pass, crypto_key)
+                        }
+                    }
+                    Ok(None) => Declined,
                     Err(e) => {
-                        eprintln!("[GATT] Invalid MAC address format: {}", e);
-                        return ConnectionFailed;
+                        eprintln!("[GATT] Key exchange failed: {}", e);
+                        ConnectionFailed
                     }
-                };
-                let crypto_key = crypto::crypto::generate_encryption_key();
-                let net_pass =
-                    crypto::crypto::generate_network_password(&device_info.name, &crypto_key);
+                }
+            }
 
-                match adapter.serve_gatt(crypto_key, device_address).await {
-                    Ok(_) => {
-                        StartingHotspot(device_info, net_pass)
+            WaitingOnLan(device_info, net_pass, crypto_key) => {
+                eprintln!("[LAN] Waiting for the receiver on the local network...");
+                match backends.transport.accept(&crypto_key, LAN_WAIT).await {
+                    Ok(peer) => {
+                        eprintln!("[LAN] Receiver at {} proved the session key.", peer);
+                        SendingOverLan(device_info, net_pass, crypto_key)
+                    }
+                    Err(TransferError::Interrupted(e)) => {
+                        eprintln!("[LAN] Not reachable ({}). Falling back to a hotspot.", e);
+                        StartingHotspot(device_info, net_pass, crypto_key)
                     }
                     Err(e) => {
-                        eprintln!("[GATT] Failed to start GATT server: {}", e);
-                        ConnectionFailed
+                        eprintln!("[LAN] Failed: {}", e);
+                        SendFailed
                     }
                 }
             }
 
-            StartingHotspot(device_info, net_pass) => {
+            SendingOverLan(device_info, net_pass, crypto_key) => {
+                eprintln!("[LAN] Starting encrypted transfer of {}", payload.describe());
+                match backends.transport.send(&mut payload).await {
+                    Ok(_) => SendSuccess,
+                    Err(TransferError::Interrupted(e)) => {
+                        eprintln!("[LAN] Link dropped ({}). Falling back to a hotspot.", e);
+                        StartingHotspot(device_info, net_pass, crypto_key)
+                    }
+                    Err(e) => {
+                        eprintln!("[LAN] Failed: {}", e);
+                        SendFailed
+                    }
+                }
+            }
+
+            StartingHotspot(device_info, net_pass, crypto_key) => {
                 //Use receiver's hostname + BT MAC to create deterministic SSID
                 let hostname = device_info.name.clone();
                 let mac_fragment = device_info.address.replace(":", "").to_lowercase();
                 let suffix = &mac_fragment[mac_fragment.len() - 4..];
                 let ssid = format!("fling-{}-{}-{}", hostname, suffix, &net_pass[net_pass.len()-2..]);
-                match tunnel::connection::create_wifi_direct_network(&ssid, &net_pass).await {
-                    Ok(_) => {
-                        println!("[Hotspot] AP live. Waiting for receiver to join...");
-                        WaitingForJoin(device_info)
+                // Even a failed attempt may have disconnected the interface.
+                hosting = true;
+                let hosted = match backends.link.host(&ssid, &net_pass).await {
+                    Ok(ip) => backends.transport.listen_on(ip).await.map(|_| ip),
+                    Err(e) => Err(e),
+                };
+                match hosted {
+                    Ok(ip) => {
+                        eprintln!("[Hotspot] AP live at {}. Waiting for receiver to join...", ip);
+                        WaitingForJoin(device_info, crypto_key)
                     }
                     Err(e) => {
                         eprintln!("[Hotspot] Failed: {}", e);
@@ -109,12 +193,12 @@ pub async fn start_sender_fsm(filepath: &str) -> SenderState {
                 }
             }
 
-            WaitingForJoin(_device_info) => {
-                println!("[WaitingForJoin] Polling for client...");
-                match tunnel::connection::wait_for_receiver().await {
-                    Ok(_) => {
-                        println!("[WaitingForJoin] Receiver joined the network!");
-                        Sending
+            WaitingForJoin(device_info, crypto_key) => {
+                eprintln!("[WaitingForJoin] Waiting for the receiver to connect...");
+                match backends.transport.accept(&crypto_key, ACCEPT_WAIT).await {
+                    Ok(peer) => {
+                        eprintln!("[WaitingForJoin] Receiver at {} proved the session key.", peer);
+                        Sending(device_info, crypto_key)
                     }
                     Err(e) => {
                         eprintln!("[WaitingForJoin] Timeout or failure: {}", e);
@@ -123,10 +207,18 @@ pub async fn start_sender_fsm(filepath: &str) -> SenderState {
                 }
             }
 
-            Sending => {
-                println!("[Sending] Starting file transfer: {}", filepath);
-                match tunnel::transfer::send_file(filepath).await {
+            Sending(device_info, crypto_key) => {
+                eprintln!("[Sending] Starting encrypted transfer of {}", payload.describe());
+                match backends.transport.send(&mut payload).await {
                     Ok(_) => SendSuccess,
+                    Err(TransferError::Interrupted(e)) if resumes < MAX_RESUMES => {
+                        resumes += 1;
+                        eprintln!(
+                            "[Sending] Link dropped ({}). Waiting for the receiver to resume ({}/{})...",
+                            e, resumes, MAX_RESUMES
+                        );
+                        WaitingForJoin(device_info, crypto_key)
+                    }
                     Err(e) => {
                         eprintln!("[Sending] Failed: {}", e);
                         SendFailed
@@ -135,28 +227,66 @@ pub async fn start_sender_fsm(filepath: &str) -> SenderState {
             }
 
             SendSuccess => {
-                println!("[✅] Transfer complete!");
-                tunnel::connection::cleanup_wifi().await;
+                eprintln!("[✅] Transfer complete!");
+                if hosting {
+                    backends.link.teardown().await;
+                }
                 break SendSuccess;
             }
 
             SendFailed => {
-                println!("[❌] Transfer failed.");
-                tunnel::connection::cleanup_wifi().await;
+                eprintln!("[❌] Transfer failed.");
+                if hosting {
+                    backends.link.teardown().await;
+                }
                 break SendFailed;
             }
 
+            // Nothing was brought up yet, so there is nothing to clean up.
+            Declined => {
+                eprintln!("[Declined] The receiver declined the transfer.");
+                break Declined;
+            }
+
             NoDevicesFound => {
-                println!("[NoDevicesFound] Exiting.");
-                tunnel::connection::cleanup_wifi().await;
+                eprintln!("[NoDevicesFound] Exiting.");
+                if hosting {
+                    backends.link.teardown().await;
+                }
                 break NoDevicesFound;
             }
 
             ConnectionFailed => {
-                println!("[ConnectionFailed] Exiting.");
-                tunnel::connection::cleanup_wifi().await;
+                eprintln!("[ConnectionFailed] Exiting.");
+                if hosting {
+                    backends.link.teardown().await;
+                }
                 break ConnectionFailed;
             }
         };
     }
 }
+
+/// The first device on `devices` that `target` names, waiting up to
+/// `TARGET_WAIT` for it.
+async fn first_match(
+    mut devices: mpsc::UnboundedReceiver<Sighting>,
+    target: &Target,
+) -> Option<DeviceInfo> {
+    eprintln!("[Scanning] Looking for {}...", target);
+    let matching = async {
+        while let Some(sighting) = devices.recv().await {
+            if let Sighting::Seen(device) = sighting
+                && target.matches(&device)
+            {
+                return Some(device);
+            }
+        }
+        None
+    };
+    let chosen = timeout(TARGET_WAIT, matching).await.ok().flatten();
+    if chosen.is_none() {
+        eprintln!("[Scanning] Did not find {}.", target);
+    }
+    chosen
+}
@@ -0,0 +1,7 @@
+pub mod crypto;
+pub mod fsm;
+pub mod linux;
+pub mod macos;
+pub mod platform;
+pub mod tunnel;
+pub mod utils;
@@ -0,0 +1,172 @@
+use crate::linux::bluetooth::{AdapterController, PendingPairing, get_bluetooth_mac, start_key_exchange};
+use crate::linux::{connection, transfer};
+use crate::platform::mdns::{Mdns, WithMdns};
+use crate::platform::terminal::Terminal;
+use crate::platform::{
+    Backends, DeviceInfo, Discovery, KeyExchange, LinkOptions, LinkProvider, Listening, Sighting,
+    Transport, Via,
+};
+use crate::tunnel::transfer::{Destination, Offer, Payload, TransferError};
+use std::net::{IpAddr, SocketAddr};
+use std::sync::{Arc, Mutex, OnceLock};
+use std::time::Duration;
+use tokio::net::{TcpListener, TcpStream};
+use tokio::sync::mpsc;
+
+/// BlueZ: scanning, the GATT server and the GATT client.
+#[derive(Clone)]
+pub struct Bluez {
+    adapter: AdapterController,
+}
+
+impl Discovery for Bluez {
+    async fn scan(&self, found: mpsc::UnboundedSender<Sighting>) -> Result<(), String> {
+        self.adapter.scan_devices(found).await.map_err(|e| e.to_string())
+    }
+
+    fn local_name(&self) -> String {
+        whoami::fallible::hostname().unwrap_or_else(|_| "unknown".to_string())
+    }
+
+    fn local_address(&self) -> Option<String> {
+        get_bluetooth_mac()
+    }
+}
+
+impl KeyExchange for Bluez {
+    type Pairing = PendingPairing;
+
+    async fn offer(&self, peer: &DeviceInfo, offer: &Offer) -> Result<Option<Vec<u8>>, String> {
+        let Via::Bluetooth(seen) = &peer.via else {
+            return Err(format!("{} was not found over Bluetooth", peer));
+        };
+        let address = seen
+            .parse::<bluer::Address>()
+            .map_err(|e| format!("Invalid MAC address format: {}", e))?;
+        self.adapter.serve_gatt(address, offer).await.map_err(|e| e.to_string())
+    }
+
+    async fn accept(&self) -> Result<PendingPairing, String> {
+        let name = self.local_name();
+        start_key_exchange(&name, self.local_address()).await.map_err(|e| e.to_string())
+    }
+}
+
+/// A Wi-Fi Direct group through wpa_supplicant, or a NetworkManager hotspot
+/// where that is unavailable; the receiver joins either through
+/// NetworkManager.
+pub struct WifiLink {
+    options: LinkOptions,
+}
+
+impl LinkProvider for WifiLink {
+    async fn host(&self, ssid: &str, password: &str) -> Result<IpAddr, String> {
+        let ip = connection::create_wifi_direct_network(&self.options, ssid, password).await?;
+        Ok(IpAddr::V4(ip))
+    }
+
+    async fn join(&self, ssid: &str, password: &str) -> Result<IpAddr, String> {
+        let iface = self.options.iface.as_deref();
+        let sender = connection::join_wifi_direct_network(iface, ssid, password).await?;
+        Ok(IpAddr::V4(sender))
+    }
+
+    async fn teardown(&self) {
+        connection::cleanup_wifi().await;
+    }
+}
+
+/// The encrypted session over TCP, on the LAN or the hotspot.
+#[derive(Default)]
+pub struct TcpTransport {
+    /// The LAN listeners, then the hotspot's once it is up.
+    listeners: Mutex<Vec<Arc<TcpListener>>>,
+    /// What `listen` advertised.
+    listening: OnceLock<Listening>,
+    /// Holds the advertised port until the hotspot listener takes it, when
+    /// no LAN listener does.
+    reservation: Mutex<Option<TcpListener>>,
+    /// The connection `accept` verified, with its key, for `send`.
+    accepted: Mutex<Option<(TcpStream, Vec<u8>)>>,
+}
+
+impl Transport for TcpTransport {
+    async fn listen(&self) -> Result<Listening, String> {
+        if let Some(listening) = self.listening.get() {
+            return Ok(listening.clone());
+        }
+        let (listeners, reservation, listening) = transfer::listen().await?;
+        self.listeners.lock().unwrap().extend(listeners.into_iter().map(Arc::new));
+        *self.reservation.lock().unwrap() = reservation;
+        Ok(self.listening.get_or_init(|| listening).clone())
+    }
+
+    async fn listen_on(&self, ip: IpAddr) -> Result<(), String> {
+        let port = self.listening.get().ok_or("Not listening for the receiver")?.port;
+        let listener = transfer::listen_on(ip, port).await?;
+        self.listeners.lock().unwrap().push(Arc::new(listener));
+        self.reservation.lock().unwrap().take();
+        Ok(())
+    }
+
+    async fn accept(&self, key: &[u8], wait: Duration) -> Result<SocketAddr, TransferError> {
+        let listeners = self.listeners.lock().unwrap().clone();
+        let (socket, peer, connection_key) =
+            transfer::accept_receiver(&listeners, key, wait).await?;
+        *self.accepted.lock().unwrap() = Some((socket, connection_key));
+        Ok(peer)
+    }
+
+    async fn send(&self, payload: &mut Payload) -> Result<(), TransferError> {
+        let (socket, connection_key) = self
+            .accepted
+            .lock()
+            .unwrap()
+            .take()
+            .ok_or_else(|| TransferError::Fatal("No verified receiver to send to".into()))?;
+        transfer::send_file(socket, payload, &connection_key).await
+    }
+
+    async fn receive(
+        &self,
+        peers: &[SocketAddr],
+        wait: Duration,
+        destination: &Destination,
+        key: &[u8],
+    ) -> Result<(), TransferError> {
+        transfer::receive_file(peers, wait, destination, key).await
+    }
+}
+
+pub type NativeBackends =
+    Backends<WithMdns<Bluez>, WithMdns<Bluez>, WifiLink, TcpTransport, Terminal>;
+
+/// Bluetooth and mDNS, whichever of the two this machine can use.
+pub async fn native(link: LinkOptions) -> Result<NativeBackends, String> {
+    let radio = match AdapterController::initialize().await {
+        Ok(adapter) => Some(Bluez { adapter }),
+        Err(e) => {
+            eprintln!("[Bluetooth] Unavailable ({}); using mDNS only.", e);
+            None
+        }
+    };
+    let mdns = match Mdns::new() {
+        Ok(mdns) => Some(mdns),
+        Err(e) => {
+            eprintln!("[mDNS] Unavailable ({}).", e);
+            None
+        }
+    };
+    if radio.is_none() && mdns.is_none() {
+        return Err("Neither Bluetooth nor mDNS is available".into());
+    }
+
+    let discovery = WithMdns { radio, mdns };
+    Ok(Backends {
+        discovery: discovery.clone(),
+        key_exchange: discovery,
+        link: WifiLink { options: link },
+        transport: TcpTransport::default(),
+        ui: Terminal,
+    })
+}
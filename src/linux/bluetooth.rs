@@ -1,26 +1,78 @@
-use bluer::{Adapter, AdapterEvent, Address, Session};
+use bluer::{Adapter, AdapterEvent, Address, AddressType, Session};
 use futures::{StreamExt};
 use std::fmt;
 use std::process::Command;
-use bluer::Uuid;
+use bluer::gatt::local::ReqError;
 use std::error::Error;
 use std::time::{Duration, Instant};
+use tokio::sync::mpsc;
 use tokio::time::sleep;
-pub struct AdapterController {
-    adapter: Adapter
+use crate::crypto::handshake::{Handshake, Role, SessionKeys, Verdict};
+use crate::crypto::stream::{open_message, seal_message};
+use crate::linux::radio;
+use crate::platform::{DeviceInfo, Pairing, Sighting, Via, gatt};
+use crate::tunnel::transfer::Offer;
+
+/// How long the sender waits for the receiver to pair and confirm the code.
+const PAIRING_TIMEOUT: Duration = Duration::from_secs(60);
+
+/// The one device allowed to use the fling GATT service: the one the user
+/// picked.
+///
+/// With LE privacy a device shows a resolvable private address that changes
+/// every few minutes, and BlueZ files requests under the device object for
+/// whatever address the link came up on. Once BlueZ holds the device's
+/// identity key it resolves that address and reports the identity address in
+/// the object's `Address` property, so both sides are compared by that.
+#[derive(Clone)]
+struct SelectedPeer {
+    adapter: Adapter,
+    expected: Address,
 }
 
-#[derive(Clone, Debug)]
-pub struct DeviceInfo {
-    pub name: String,
-    pub address: String,
-}
-impl fmt::Display for DeviceInfo {
-    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
-        write!(f, "{} ({})", self.name, self.address)
+impl SelectedPeer {
+    async fn identity(&self, address: Address) -> Address {
+        match self.adapter.device(address) {
+            Ok(device) => device.remote_address().await.unwrap_or(address),
+            Err(_) => address,
+        }
+    }
+
+    /// Lets `peer` through if it is the selected device, and logs it otherwise.
+    async fn check(&self, peer: Address, request: &str) -> Result<(), ReqError> {
+        if peer == self.expected || self.identity(peer).await == self.identity(self.expected).await
+        {
+            return Ok(());
+        }
+        eprintln!(
+            "[Bluetooth] Rejected {} from {}: not the selected device ({}).",
+            request, peer, self.expected
+        );
+        if self.is_private(peer).await {
+            eprintln!(
+                "[Bluetooth] {} is a private address BlueZ could not resolve; if it is the \
+                 receiver, pair the two devices once so it can.",
+                peer
+            );
+        }
+        Err(ReqError::NotPermitted)
+    }
+
+    /// Whether `address` is a resolvable private address: random, with the
+    /// two most significant bits `01`.
+    async fn is_private(&self, address: Address) -> bool {
+        let Ok(device) = self.adapter.device(address) else {
+            return false;
+        };
+        device.address_type().await.ok() == Some(AddressType::LeRandom) && address.0[0] >> 6 == 0b01
     }
 }
 
+#[derive(Clone)]
+pub struct AdapterController {
+    adapter: Adapter
+}
+
 impl AdapterController {
     pub async fn initialize() -> bluer::Result<Self> {
         let session = bluer::Session::new().await?;
@@ -29,176 +81,388 @@ impl AdapterController {
 
         Ok(Self { adapter })
     }
+    /// Reports the receivers advertising the fling service to `found`
+    /// until dropped, again whenever one's signal strength or advertisement
+    /// changes, and once more when BlueZ forgets it.
     pub async fn scan_devices(
         &self,
-        timeout_secs: Duration,
-    ) -> Result<Vec<DeviceInfo>, Box<dyn std::error::Error>> {
-        use tokio::time::{Duration, Instant, sleep};
-
-        println!("[Bluetooth] Scanning for nearby devices...");
-
-        let mut events = self.adapter.discover_devices().await?;
-        let deadline = Instant::now() + timeout_secs;
-
-        let mut found_devices = vec![];
-
-        while Instant::now() < deadline {
-            tokio::select! {
-                maybe_event = events.next() => {
-                    if let Some(AdapterEvent::DeviceAdded(addr)) = maybe_event {
-                        let device = self.adapter.device(addr)?;
-                        let name = device.name().await?.unwrap_or("Unnamed".into());
-
-                        let info = DeviceInfo {
-                            name: name.clone(),
-                            address: addr.to_string(),
-                        };
-
-                        println!("  → Found: {}", info);
-                        found_devices.push(info);
+        found: mpsc::UnboundedSender<Sighting>,
+    ) -> Result<(), Box<dyn std::error::Error>> {
+        eprintln!("[Bluetooth] Scanning for nearby receivers...");
+
+        // The advertisement and the scan response, which carries the name,
+        // may arrive after the device first shows up; every change to them
+        // is reported as the device being added again.
+        let mut events = self.adapter.discover_devices_with_changes().await?;
+        while let Some(event) = events.next().await {
+            let sighting = match event {
+                AdapterEvent::DeviceAdded(addr) => match self.receiver(addr).await {
+                    Ok(Some(info)) => Sighting::Seen(info),
+                    Ok(None) => continue,
+                    // One device going away mid-read must not end the scan.
+                    Err(e) => {
+                        eprintln!("[Bluetooth] Cannot read {}: {}", addr, e);
+                        continue;
                     }
+                },
+                AdapterEvent::DeviceRemoved(addr) => {
+                    Sighting::Lost(Via::Bluetooth(addr.to_string()))
                 }
-
-                _ = sleep(Duration::from_millis(200)) => {
-                }
+                _ => continue,
+            };
+            if found.send(sighting).is_err() {
+                break;
             }
         }
+        Ok(())
+    }
 
-        println!(
-            "[Bluetooth] Scan complete. Found {} devices.",
-            found_devices.len()
-        );
-        Ok(found_devices)
+    /// The receiver at `addr`, if it is currently advertising the fling
+    /// service in a version this build speaks.
+    async fn receiver(&self, addr: Address) -> bluer::Result<Option<DeviceInfo>> {
+        let device = self.adapter.device(addr)?;
+        // Devices BlueZ only remembers from an earlier scan have no signal.
+        let Some(rssi) = device.rssi().await? else {
+            return Ok(None);
+        };
+        let data = device.service_data().await?.and_then(|mut data| data.remove(&gatt::RECEIVER));
+        let Some(data) = data else {
+            return Ok(None);
+        };
+        let advert = match gatt::ReceiverAdvert::decode(&data) {
+            Ok(advert) => advert,
+            Err(e) => {
+                eprintln!("[Bluetooth] Ignoring receiver {}: {}", addr, e);
+                return Ok(None);
+            }
+        };
+        let alias = device.alias().await?;
+        let (name, alias) = match device.name().await? {
+            Some(name) if name != alias => (name, Some(alias)),
+            Some(name) => (name, None),
+            None => (alias, None),
+        };
+        Ok(Some(DeviceInfo {
+            name,
+            address: advert.id,
+            via: Via::Bluetooth(addr.to_string()),
+            alias,
+            rssi: Some(rssi),
+        }))
     }
 
+    /// Serves the fling GATT service and runs the sender half of the key
+    /// exchange. Once the key is agreed, `offer` is readable (sealed with it)
+    /// so the receiver can decide. Returns the session key once the receiver
+    /// has confirmed the verification code and accepted, or `None` if it
+    /// declined the offer.
     pub async fn serve_gatt(
         &self,
-        key_data: Vec<u8>,
         expected_mac: bluer::Address,
-    ) -> Result<(), Box<dyn std::error::Error>> {
-        use tokio::sync::Mutex;
-        use std::sync::{Arc, atomic::{AtomicBool, Ordering}};
+        offer: &Offer,
+    ) -> Result<Option<Vec<u8>>, Box<dyn std::error::Error>> {
+        use tokio::sync::{Mutex, mpsc};
+        use std::sync::Arc;
         use std::time::Duration;
         use bluer::gatt::local::{
-            Application, Service, Characteristic, CharacteristicRead,
-            CharacteristicReadRequest
+            Application, Service, Characteristic, CharacteristicNotify,
+            CharacteristicNotifyMethod, CharacteristicNotifier, CharacteristicRead,
+            CharacteristicReadRequest, CharacteristicWrite, CharacteristicWriteMethod,
+            CharacteristicWriteRequest
         };
         use bluer::adv::{Advertisement, Type};
-        use uuid::Uuid;
-    
-        let key_data = Arc::new(Mutex::new(key_data));
-        let expected_mac = Arc::new(expected_mac);
-        let key_read = Arc::new(AtomicBool::new(false));
-    
-        let service_uuid = Uuid::parse_str("12345678-1234-5678-1234-56789abcdef0")?;
-        let char_uuid = Uuid::parse_str("abcdef12-3456-7890-abcd-ef1234567890")?;
-    
+
+        let handshake = Handshake::new(Role::Sender);
+        let sender_public = handshake.public_key();
+        let handshake = Arc::new(Mutex::new(Some(handshake)));
+        let session_key: Arc<Mutex<Option<Vec<u8>>>> = Arc::new(Mutex::new(None));
+        let sealed_offer: Arc<Mutex<Option<Vec<u8>>>> = Arc::new(Mutex::new(None));
+        let identity = gatt::identity(&offer.sender);
+        let offer = Arc::new(offer.encode());
+        let (confirm_tx, mut confirm_rx) = mpsc::channel::<Verdict>(1);
+        let selected = SelectedPeer { adapter: self.adapter.clone(), expected: expected_mac };
+
         let app = Application {
             services: vec![Service {
-                uuid: service_uuid,
+                uuid: gatt::SERVICE,
                 primary: true,
-                characteristics: vec![Characteristic {
-                    uuid: char_uuid,
-                    read: Some(CharacteristicRead {
-                        read: true,
-                        fun: {
-                            let key_data = Arc::clone(&key_data);
-                            let expected_mac = Arc::clone(&expected_mac);
-                            let key_read = Arc::clone(&key_read);
-                            Box::new(move |req: CharacteristicReadRequest| {
-                                let key_data = Arc::clone(&key_data);
-                                let expected_mac = Arc::clone(&expected_mac);
-                                let key_read = Arc::clone(&key_read);
-                                Box::pin(async move {
-                                    // For testing, you might want to temporarily remove MAC check
-                                    println!(
-                                        "[Bluetooth] Read request from: {:?}, expected: {:?}",
-                                        req.device_address, *expected_mac
-                                    );
-                                    
-                                    // Temporarily allow any device for testing
-                                    // if req.device_address == *expected_mac {
-                                        let data = key_data.lock().await.clone();
-                                        println!(
-                                            "[Bluetooth] Key read by device: {:?}",
-                                            req.device_address
-                                        );
-                                        key_read.store(true, Ordering::SeqCst);
-                                        Ok(data)
-                                    // } else {
-                                    //     println!(
-                                    //         "[Bluetooth] Unauthorized read attempt from: {:?}",
-                                    //         req.device_address
-                                    //     );
-                                    //     Err(ReqError::NotPermitted)
-                                    // }
+                characteristics: vec![
+                    fixed_characteristic(gatt::VERSION, gatt::version()),
+                    fixed_characteristic(gatt::IDENTITY, identity),
+                    Characteristic {
+                        uuid: gatt::KEY_EXCHANGE,
+                        write: Some(CharacteristicWrite {
+                            write: true,
+                            method: CharacteristicWriteMethod::Fun({
+                                let handshake = Arc::clone(&handshake);
+                                let session_key = Arc::clone(&session_key);
+                                let sealed_offer = Arc::clone(&sealed_offer);
+                                let offer = Arc::clone(&offer);
+                                let selected = selected.clone();
+                                Box::new(move |peer_public: Vec<u8>, req: CharacteristicWriteRequest| {
+                                    let handshake = Arc::clone(&handshake);
+                                    let session_key = Arc::clone(&session_key);
+                                    let sealed_offer = Arc::clone(&sealed_offer);
+                                    let offer = Arc::clone(&offer);
+                                    let selected = selected.clone();
+                                    Box::pin(async move {
+                                        selected.check(req.device_address, "key exchange").await?;
+                                        let peer = req.device_address;
+                                        eprintln!("[Bluetooth] Key exchange from {}", peer);
+                                        let Some(handshake) = handshake.lock().await.take() else {
+                                            eprintln!("[Bluetooth] Ignoring repeated key exchange from {:?}", req.device_address);
+                                            return Err(ReqError::NotPermitted);
+                                        };
+                                        match handshake.finish(&peer_public) {
+                                            Ok(keys) => {
+                                                eprintln!(
+                                                    "[Pairing] Verification code: {} (check that the receiver shows the same)",
+                                                    keys.sas
+                                                );
+                                                match seal_message(&keys.key, &offer) {
+                                                    Ok(sealed) => *sealed_offer.lock().await = Some(sealed),
+                                                    Err(e) => eprintln!("[Bluetooth] Failed to seal offer: {}", e),
+                                                }
+                                                *session_key.lock().await = Some(keys.key);
+                                                Ok(())
+                                            }
+                                            Err(e) => {
+                                                eprintln!("[Bluetooth] Key exchange failed: {}", e);
+                                                Err(ReqError::InvalidValueLength)
+                                            }
+                                        }
+                                    })
                                 })
-                            })
-                        },
+                            }),
+                            ..Default::default()
+                        }),
+                        notify: Some(CharacteristicNotify {
+                            notify: true,
+                            method: CharacteristicNotifyMethod::Fun({
+                                let sender_public = sender_public.clone();
+                                Box::new(move |mut notifier: CharacteristicNotifier| {
+                                    let sender_public = sender_public.clone();
+                                    Box::pin(async move {
+                                        // Give the central a moment to finish subscribing.
+                                        tokio::time::sleep(Duration::from_millis(200)).await;
+                                        if let Err(e) = notifier.notify(sender_public).await {
+                                            eprintln!("[Bluetooth] Failed to send public key: {}", e);
+                                        }
+                                    })
+                                })
+                            }),
+                            ..Default::default()
+                        }),
+                        ..Default::default()
+                    },
+                    Characteristic {
+                        uuid: gatt::OFFER,
+                        read: Some(CharacteristicRead {
+                            read: true,
+                            fun: {
+                                let sealed_offer = Arc::clone(&sealed_offer);
+                                let selected = selected.clone();
+                                Box::new(move |req: CharacteristicReadRequest| {
+                                    let sealed_offer = Arc::clone(&sealed_offer);
+                                    let selected = selected.clone();
+                                    Box::pin(async move {
+                                        selected.check(req.device_address, "offer read").await?;
+                                        // Only readable once the key exchange has sealed it.
+                                        match &*sealed_offer.lock().await {
+                                            Some(sealed) => Ok(sealed
+                                                .get(req.offset as usize..)
+                                                .unwrap_or_default()
+                                                .to_vec()),
+                                            None => Err(ReqError::NotAuthorized),
+                                        }
+                                    })
+                                })
+                            },
+                            ..Default::default()
+                        }),
                         ..Default::default()
-                    }),
-                    ..Default::default()
-                }],
+                    },
+                    Characteristic {
+                        uuid: gatt::CONFIRM,
+                        write: Some(CharacteristicWrite {
+                            write: true,
+                            method: CharacteristicWriteMethod::Fun({
+                                let confirm_tx = confirm_tx.clone();
+                                let selected = selected.clone();
+                                Box::new(move |value: Vec<u8>, req: CharacteristicWriteRequest| {
+                                    let confirm_tx = confirm_tx.clone();
+                                    let selected = selected.clone();
+                                    Box::pin(async move {
+                                        selected.check(req.device_address, "verdict").await?;
+                                        let Some(verdict) = Verdict::from_byte(&value) else {
+                                            return Err(ReqError::InvalidValueLength);
+                                        };
+                                        let _ = confirm_tx.try_send(verdict);
+                                        Ok(())
+                                    })
+                                })
+                            }),
+                            ..Default::default()
+                        }),
+                        ..Default::default()
+                    },
+                ],
                 ..Default::default()
             }],
             ..Default::default()
         };
-    
-        println!("[Bluetooth] Registering GATT application...");
-        
+
+        eprintln!("[Bluetooth] Registering GATT application...");
+
         let app_handle = self.adapter.serve_gatt_application(app).await?;
-        
+
         tokio::time::sleep(Duration::from_secs(1)).await;
-        
+
         let adv = Advertisement {
             advertisement_type: Type::Peripheral,
-            service_uuids: vec![service_uuid].into_iter().collect(),
+            service_uuids: vec![gatt::SERVICE].into_iter().collect(),
             discoverable: Some(true),
             local_name: Some("fling-sender".to_string()),
             ..Default::default()
         };
-    
-        println!("[Bluetooth] Starting advertisement with service UUID: {}", service_uuid);
+
+        eprintln!("[Bluetooth] Starting advertisement with service UUID: {}", gatt::SERVICE);
         let adv_handle = self.adapter.advertise(adv).await?;
-    
-        println!("[Bluetooth] GATT server ready, waiting for device to read key (30s timeout)...");
-        
-        for i in 0..30 {
-            if key_read.load(Ordering::SeqCst) {
-                println!("[Bluetooth] Key successfully read, waiting 4s before terminating...");
-                tokio::time::sleep(Duration::from_secs(4)).await;
-                break;
-            }
-            if i % 5 == 0 {
-                println!("[Bluetooth] Still waiting... ({}/30s)", i);
-            }
-            tokio::time::sleep(Duration::from_secs(1)).await;
-        }
-    
-        if !key_read.load(Ordering::SeqCst) {
-            println!("[Bluetooth] Timeout: Key was not read within 30 seconds");
-        }
-    
+
+        eprintln!(
+            "[Bluetooth] GATT server ready, waiting for the receiver to pair ({}s timeout)...",
+            PAIRING_TIMEOUT.as_secs()
+        );
+        let confirmed = tokio::time::timeout(PAIRING_TIMEOUT, confirm_rx.recv()).await;
+
         // Clean up
         drop(adv_handle);
         drop(app_handle);
-        
-        println!("[Bluetooth] GATT server terminated");
-        Ok(())
+        eprintln!("[Bluetooth] GATT server terminated");
+
+        match confirmed {
+            Ok(Some(Verdict::Accept)) => session_key
+                .lock()
+                .await
+                .take()
+                .map(Some)
+                .ok_or_else(|| "Receiver confirmed before completing the key exchange".into()),
+            Ok(Some(Verdict::Decline)) => Ok(None),
+            Ok(Some(Verdict::CodeMismatch)) => Err("Receiver rejected the verification code".into()),
+            Ok(None) | Err(_) => Err(format!(
+                "Pairing was not completed within {} seconds",
+                PAIRING_TIMEOUT.as_secs()
+            )
+            .into()),
+        }
+    }
+}
+
+/// A read-only characteristic serving `value`.
+fn fixed_characteristic(uuid: bluer::Uuid, value: Vec<u8>) -> bluer::gatt::local::Characteristic {
+    use bluer::gatt::local::{Characteristic, CharacteristicRead, CharacteristicReadRequest};
+
+    let value = std::sync::Arc::new(value);
+    Characteristic {
+        uuid,
+        read: Some(CharacteristicRead {
+            read: true,
+            fun: Box::new(move |req: CharacteristicReadRequest| {
+                let value = std::sync::Arc::clone(&value);
+                Box::pin(async move {
+                    Ok(value.get(req.offset as usize..).unwrap_or_default().to_vec())
+                })
+            }),
+            ..Default::default()
+        }),
+        ..Default::default()
+    }
+}
+
+/// A key exchange that has finished on the wire but still waits for the user
+/// to compare the verification code.
+pub struct PendingPairing {
+    device: bluer::Device,
+    confirm_char: bluer::gatt::remote::Characteristic,
+    keys: SessionKeys,
+    offer: Offer,
+}
+
+impl fmt::Debug for PendingPairing {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "PendingPairing({})", self.device.address())
+    }
+}
+
+impl Pairing for PendingPairing {
+    fn sas(&self) -> &str {
+        &self.keys.sas
+    }
+
+    fn offer(&self) -> &Offer {
+        &self.offer
+    }
+
+    async fn respond(self, verdict: Verdict) -> Result<Vec<u8>, String> {
+        let result = self.confirm_char.write(&[verdict.to_byte()]).await;
+        let _ = self.device.disconnect().await;
+        eprintln!("[Linux] Disconnected.");
+        result.map_err(|e| format!("Failed to send verdict: {}", e))?;
+
+        match verdict {
+            Verdict::Accept => Ok(self.keys.key),
+            Verdict::Decline => Err("Transfer declined".into()),
+            Verdict::CodeMismatch => Err("Verification code rejected".into()),
+        }
     }
 }
 
-pub async fn receive_fling_key() -> Result<Vec<u8>, Box<dyn Error>> {
+/// Advertises this machine as a receiver named `name`, so senders list it.
+/// `id` is the Bluetooth address the network credentials are derived from.
+async fn advertise_receiver(
+    adapter: &Adapter,
+    name: &str,
+    id: Option<String>,
+) -> Result<bluer::adv::AdvertisementHandle, Box<dyn Error>> {
+    use bluer::adv::{Advertisement, Type};
+
+    let id = match id {
+        Some(id) => id,
+        None => adapter.address().await?.to_string(),
+    };
+    let mut capabilities = gatt::USES_LAN;
+    if radio::has_wireless_interface() {
+        capabilities |= gatt::JOINS_WIFI;
+    }
+    let advert = gatt::ReceiverAdvert { capabilities, id };
+    let adv = Advertisement {
+        advertisement_type: Type::Peripheral,
+        service_data: [(gatt::RECEIVER, advert.encode()?)].into_iter().collect(),
+        discoverable: Some(true),
+        local_name: Some(name.to_string()),
+        ..Default::default()
+    };
+    eprintln!("[Linux] Advertising as receiver \"{}\" ({})", name, advert.describe());
+    Ok(adapter.advertise(adv).await?)
+}
 
-    let service_uuid = Uuid::parse_str("12345678-1234-5678-1234-56789abcdef0")?;
-    let char_uuid = Uuid::parse_str("abcdef12-3456-7890-abcd-ef1234567890")?;
+/// Advertises this machine as receiver `name`, finds the advertising fling
+/// sender, runs the receiver half of the key exchange and returns it pending
+/// the user's code comparison.
+pub async fn start_key_exchange(
+    name: &str,
+    id: Option<String>,
+) -> Result<PendingPairing, Box<dyn Error>> {
 
     let session: Session = Session::new().await?;
     let adapter = session.default_adapter().await?;
     adapter.set_powered(true).await?;
-    println!("[Linux] Adapter powered: {}", adapter.name());
+    eprintln!("[Linux] Adapter powered: {}", adapter.name());
+
+    // Withdrawn once the key exchange ends, however it ends.
+    let _advertisement = advertise_receiver(&adapter, name, id).await?;
 
-    println!("[Linux] Scanning for fling sender ({}s timeout)...", 30);
+    eprintln!("[Linux] Scanning for fling sender ({}s timeout)...", 30);
     let mut events = adapter.discover_devices().await?;
 
     let scan_deadline = Instant::now() + Duration::from_secs(30);
@@ -211,16 +475,14 @@ pub async fn receive_fling_key() -> Result<Vec<u8>, Box<dyn Error>> {
                 if let AdapterEvent::DeviceAdded(addr) = evt {
                     let device = adapter.device(addr)?;
                     let name: Option<String> = device.name().await?;
-                    println!("[Scan] Found device: {:?} ({})", name, addr);
-
-                    if let Ok(opt_uuids) = device.uuids().await {
-                        if let Some(uuids) = opt_uuids {
-                            if uuids.contains(&service_uuid) {
-                                println!("[Scan] Device {} advertises fling service", addr);
-                                found_addr = Some(addr);
-                                break;
-                            }
-                        }
+                    eprintln!("[Scan] Found device: {:?} ({})", name, addr);
+
+                    if let Ok(Some(uuids)) = device.uuids().await
+                        && uuids.contains(&gatt::SERVICE)
+                    {
+                        eprintln!("[Scan] Device {} advertises fling service", addr);
+                        found_addr = Some(addr);
+                        break;
                     }
                 } 
             }
@@ -241,7 +503,7 @@ pub async fn receive_fling_key() -> Result<Vec<u8>, Box<dyn Error>> {
 
     drop(events);
     let device = adapter.device(addr)?;
-    println!("[Linux] Connecting to device {}", addr);
+    eprintln!("[Linux] Connecting to device {}", addr);
     device.connect().await?;
 
     // Wait for device to be connected (small polling loop)
@@ -256,19 +518,19 @@ pub async fn receive_fling_key() -> Result<Vec<u8>, Box<dyn Error>> {
         }
         sleep(Duration::from_millis(200)).await;
     }
-    println!("[Linux] Device connected.");
+    eprintln!("[Linux] Device connected.");
     let gatt_services: Vec<bluer::gatt::remote::Service> = device.services().await?;
-    println!("[Linux] Discovered {} services", gatt_services.len());
+    eprintln!("[Linux] Discovered {} services", gatt_services.len());
 
     // -------- DEMO LOG: list all services & characteristics (remove later) --------
     for svc in &gatt_services {
         let svc_uuid = svc.uuid().await?;
-        println!("  Service UUID: {}", svc_uuid);
+        eprintln!("  Service UUID: {}", svc_uuid);
         let chars = svc.characteristics().await?;
-        println!("    {} characteristics", chars.len());
+        eprintln!("    {} characteristics", chars.len());
         for ch in &chars {
             let ch_uuid = ch.uuid().await?;
-            println!("      Characteristic UUID: {}", ch_uuid);
+            eprintln!("      Characteristic UUID: {}", ch_uuid);
         }
     }
     // ---------------------------------------------------------------------------
@@ -276,7 +538,7 @@ pub async fn receive_fling_key() -> Result<Vec<u8>, Box<dyn Error>> {
     let mut fling_service_opt: Option<bluer::gatt::remote::Service> = None;
     for svc in gatt_services {
         let svc_uuid = svc.uuid().await?;
-        if svc_uuid == service_uuid {
+        if svc_uuid == gatt::SERVICE {
             fling_service_opt = Some(svc);
             break;
         }
@@ -291,32 +553,85 @@ pub async fn receive_fling_key() -> Result<Vec<u8>, Box<dyn Error>> {
     };
 
     let chars = fling_service.characteristics().await?;
-    let mut target_char_opt: Option<bluer::gatt::remote::Characteristic> = None;
+    let mut kx_char_opt: Option<bluer::gatt::remote::Characteristic> = None;
+    let mut confirm_char_opt: Option<bluer::gatt::remote::Characteristic> = None;
+    let mut offer_char_opt: Option<bluer::gatt::remote::Characteristic> = None;
+    let mut version_char_opt: Option<bluer::gatt::remote::Characteristic> = None;
+    let mut identity_char_opt: Option<bluer::gatt::remote::Characteristic> = None;
     for ch in chars {
         let ch_uuid = ch.uuid().await?;
-        if ch_uuid == char_uuid {
-            target_char_opt = Some(ch);
-            break;
+        if ch_uuid == gatt::VERSION {
+            version_char_opt = Some(ch);
+        } else if ch_uuid == gatt::IDENTITY {
+            identity_char_opt = Some(ch);
+        } else if ch_uuid == gatt::KEY_EXCHANGE {
+            kx_char_opt = Some(ch);
+        } else if ch_uuid == gatt::CONFIRM {
+            confirm_char_opt = Some(ch);
+        } else if ch_uuid == gatt::OFFER {
+            offer_char_opt = Some(ch);
         }
     }
 
-    let target_char = match target_char_opt {
-        Some(c) => c,
-        None => {
+    let (kx_char, confirm_char, offer_char) = match (kx_char_opt, confirm_char_opt, offer_char_opt) {
+        (Some(kx), Some(confirm), Some(offer)) => (kx, confirm, offer),
+        _ => {
             let _ = device.disconnect().await;
-            return Err("Fling characteristic not found in service".into());
+            return Err("Fling key exchange characteristics not found in service".into());
         }
     };
 
-    println!("[Linux] Reading fling characteristic...");
-    let key: Vec<u8> = target_char.read().await?;
+    let version = match version_char_opt {
+        Some(ch) => ch.read().await?,
+        None => Vec::new(),
+    };
+    if let Err(e) = gatt::check_version(&version) {
+        let _ = device.disconnect().await;
+        return Err(e.into());
+    }
+    if let Some(ch) = identity_char_opt {
+        eprintln!("[Linux] Sender: {}", gatt::parse_identity(&ch.read().await?));
+    }
 
-    println!("[Linux] Read {} bytes from fling characteristic", key.len());
+    eprintln!("[Linux] Starting key exchange...");
+    let mut notifications = Box::pin(kx_char.notify().await?);
+    let sender_public = match tokio::time::timeout(Duration::from_secs(10), notifications.next()).await {
+        Ok(Some(value)) => value,
+        _ => {
+            let _ = device.disconnect().await;
+            return Err("Sender did not send its public key".into());
+        }
+    };
+    drop(notifications);
+
+    let handshake = Handshake::new(Role::Receiver);
+    kx_char.write(&handshake.public_key()).await?;
+    let keys = match handshake.finish(&sender_public) {
+        Ok(keys) => keys,
+        Err(e) => {
+            let _ = device.disconnect().await;
+            return Err(e.into());
+        }
+    };
 
-    device.disconnect().await?;
-    println!("[Linux] Disconnected.");
+    let offer = match read_offer(&offer_char, &keys.key).await {
+        Ok(offer) => offer,
+        Err(e) => {
+            let _ = device.disconnect().await;
+            return Err(e);
+        }
+    };
 
-    Ok(key)
+    Ok(PendingPairing { device, confirm_char, keys, offer })
+}
+
+async fn read_offer(
+    offer_char: &bluer::gatt::remote::Characteristic,
+    key: &[u8],
+) -> Result<Offer, Box<dyn Error>> {
+    let sealed = offer_char.read().await?;
+    let offer = open_message(key, &sealed)?;
+    Ok(Offer::decode(&offer)?)
 }
 
 pub fn get_bluetooth_mac() -> Option<String> {
@@ -326,9 +641,5 @@ pub fn get_bluetooth_mac() -> Option<String> {
         .ok()?;
 
     let stdout = String::from_utf8_lossy(&output.stdout);
-    if let Some(mac) = stdout.split_whitespace().nth(1) {
-        Some(mac.to_string())
-    } else {
-        None
-    }
+    stdout.split_whitespace().nth(1).map(|mac| mac.to_string())
 }
\ No newline at end of file
@@ -1,97 +1,245 @@
-use std::{process::Command, time::Duration};
+use std::{net::Ipv4Addr, sync::Mutex, time::Duration};
+use tokio::sync::oneshot;
 use tokio::time::sleep;
 
-/// Launches a Wi-Fi Direct access point using hostapd and sets up DHCP
-pub async fn create_wifi_direct_network(ssid: &str, password: &str) -> Result<String, String> {
-    let _iface = "wlan0";
-
-    // Kill any existing hotspot connections
-    let _ = Command::new("nmcli")
-        .args(&["con", "down", "Hotspot"])
-        .output(); // ignore failure
-
-    let _ = Command::new("nmcli").args(&["dev", "disconnect", "wlan0"]).output();
-
-    let cmd = Command::new("nmcli")
-        .args(&[
-            "device", "wifi", "hotspot",
-            "ifname", "wlan0",
-            "band", "a",
-            "channel", "149",
-            "ssid", &ssid,
-            "password", &password,
-        ])
-        .output()
-        .map_err(|e| format!("Failed to spawn nmcli: {}", e))?;
-    if !cmd.status.success() {
-        return Err(format!(
-            "Hotspot creation failed: {}",
-            String::from_utf8_lossy(&cmd.stderr)
-        ));
-    }
+use crate::linux::nm::{NetworkManager, NmError, StationWatch, WifiDevice};
+use crate::linux::{p2p, radio};
+use crate::platform::LinkOptions;
+
+/// How often the receiver looks for the sender's hotspot before giving up.
+const JOIN_ATTEMPTS: u32 = 10;
+/// The NetworkManager profile fling creates for its hotspot or for joining
+/// one, so cleanup deletes exactly that and nothing of the user's.
+const PROFILE: &str = "fling";
+
+/// What fling changed on the way up, so cleanup can put it back.
+struct Changes {
+    /// The interface and the saved connection it had before, to reactivate.
+    previous: Option<(String, dbus::Path<'static>)>,
+    /// Whether `PROFILE` was created.
+    created: bool,
+    /// The interface fling's hotspot or P2P group runs on.
+    hotspot: Option<String>,
+}
 
-    // Optional: give it 2 seconds to settle
-    sleep(std::time::Duration::from_secs(2)).await;
+static CHANGES: Mutex<Changes> =
+    Mutex::new(Changes { previous: None, created: false, hotspot: None });
 
-    // Done. Return the static gateway IP that nmcli usually assigns
-    Ok("10.42.0.1".to_string()) // default IP used by nmcli hotspot
-}
+/// Stops the task logging stations on the sender's network.
+static STATIONS: Mutex<Option<oneshot::Sender<()>>> = Mutex::new(None);
 
-/// Waits up to 30 seconds for a client to join the AP
-pub async fn wait_for_receiver() -> Result<String, String> {
-    for _ in 0..30 {
-        // Check ARP table
-        let arp = Command::new("ip")
-            .args(&["neigh"])
-            .output()
-            .ok()
-            .map(|o| String::from_utf8_lossy(&o.stdout).to_string())
-            .unwrap_or_default();
-
-        for line in arp.lines() {
-            if line.contains("10.42.0.") && line.contains("lladdr") && !line.contains("FAILED") {
-                let ip = line.split_whitespace().next().unwrap_or_default().to_string();
-                println!("[ReceiverConnected] IP: {}", ip);
-                return Ok(ip);
+/// Records the connection on `device` before fling replaces it. Only the
+/// first call counts, so a retry does not snapshot fling's own profile.
+async fn remember_previous(nm: &NetworkManager, device: &WifiDevice) {
+    if CHANGES.lock().unwrap().created {
+        return;
+    }
+    match nm.active_connection(device).await {
+        Ok(Some(previous)) => {
+            let mut changes = CHANGES.lock().unwrap();
+            if changes.previous.is_none() {
+                changes.previous = Some((device.iface.clone(), previous));
             }
         }
+        Ok(None) => {}
+        Err(e) => eprintln!("[Network] Cannot tell what {} is connected to: {}", device.iface, e),
+    }
+}
+
+/// Deletes a `PROFILE` left behind by an earlier run that did not clean up.
+async fn delete_profile(nm: &NetworkManager) {
+    if let Err(e) = nm.delete_connections(PROFILE).await {
+        eprintln!("[Network] Cannot delete the '{}' profile: {}", PROFILE, e);
+    }
+}
+
+/// Brings up the network the receiver joins: a Wi-Fi Direct group through
+/// wpa_supplicant, which leaves the current connection alone, or a
+/// NetworkManager hotspot, which replaces it, where P2P is unavailable.
+/// The interface and channel are detected unless `options` names them.
+/// Returns this machine's address on the new network.
+pub async fn create_wifi_direct_network(
+    options: &LinkOptions,
+    ssid: &str,
+    password: &str,
+) -> Result<Ipv4Addr, String> {
+    let nm = NetworkManager::get().await.map_err(|e| e.to_string())?;
+    let device = nm
+        .choose_device(options.iface.as_deref(), true)
+        .await
+        .map_err(|e| format!("Hotspot failed: {}", e))?;
+    let channel = radio::choose_channel(&device, options.channel);
+    eprintln!(
+        "[Hotspot] Using {} on {} channel {}.",
+        device.iface,
+        radio::band_of(channel),
+        channel
+    );
+
+    match nm.watch_stations().await {
+        Ok(watch) => *STATIONS.lock().unwrap() = Some(log_stations(watch)),
+        Err(e) => eprintln!("[Hotspot] Cannot watch for stations: {}", e),
+    }
+
+    let group = (device.iface.clone(), ssid.to_string(), password.to_string());
+    let freq = radio::frequency(channel);
+    let started = tokio::task::spawn_blocking(move || {
+        let (iface, ssid, password) = group;
+        p2p::start_group(&iface, &ssid, &password, freq)
+    })
+    .await
+    .unwrap_or_else(|e| Err(e.to_string()));
+    match started {
+        Ok((ifname, ip)) => {
+            CHANGES.lock().unwrap().hotspot = Some(ifname);
+            // Let dnsmasq come up before the receiver asks for a lease.
+            sleep(Duration::from_secs(1)).await;
+            return Ok(ip);
+        }
+        Err(e) => eprintln!("[Hotspot] Wi-Fi Direct unavailable ({}); using a hotspot.", e),
+    }
 
-        sleep(Duration::from_secs(1)).await;
+    create_hotspot(nm, &device, ssid, password, channel)
+        .await
+        .map_err(|e| format!("Hotspot failed: {}", e))?;
+    match nm.ipv4_address(&device).await {
+        Ok(Some(ip)) => Ok(ip),
+        Ok(None) => Err(format!("The hotspot on {} has no IPv4 address", device.iface)),
+        Err(e) => Err(format!("Cannot read the hotspot's address: {}", e)),
+    }
+}
+
+/// Starts a hotspot on `device` in place of its current connection, which
+/// cleanup reactivates.
+async fn create_hotspot(
+    nm: &NetworkManager,
+    device: &WifiDevice,
+    ssid: &str,
+    password: &str,
+    channel: u32,
+) -> Result<(), NmError> {
+    let band = radio::band_of(channel);
+    device.check_ap(band)?;
+    remember_previous(nm, device).await;
+    delete_profile(nm).await;
+    {
+        let mut changes = CHANGES.lock().unwrap();
+        changes.created = true;
+        changes.hotspot = Some(device.iface.clone());
     }
 
-    Err("No clients joined within timeout".into())
+    nm.start_hotspot(device, PROFILE, ssid, password, band, channel).await
 }
 
-pub async fn cleanup_wifi() {
-    // Kill hotspot
-    let _ = Command::new("nmcli")
-        .args(&["con", "down", "Hotspot"])
-        .output();
-
-    // Restart NetworkManager service
-    let _ = Command::new("systemctl")
-        .args(&["restart", "NetworkManager"])
-        .output();
-
-    // Optionally re-enable Wi-Fi
-    let _ = Command::new("nmcli")
-        .args(&["radio", "wifi", "on"])
-        .output();
-
-    println!("[Cleanup] Wi-Fi state cleaned up and reset.");
+/// The interfaces fling itself turned into a hotspot, whose addresses are
+/// no LAN to offer a receiver.
+pub fn hotspot_interfaces() -> Vec<String> {
+    CHANGES.lock().unwrap().hotspot.iter().cloned().collect()
 }
-///Joins a Full AP network controlled by sender
-pub fn join_wifi_direct_network(ssid: &str, password: &str) -> bool {
-    use std::process::Command;
 
-    let output = Command::new("nmcli")
-        .args(&["dev", "wifi", "connect", ssid, "password", password])
-        .output();
+/// Logs stations as they join the sender's network until told to stop.
+/// Joining proves nothing about who they are; the transfer itself only
+/// starts for a peer that holds the session key.
+fn log_stations(mut watch: StationWatch) -> oneshot::Sender<()> {
+    let (stop_tx, mut stop) = oneshot::channel();
+    tokio::spawn(async move {
+        loop {
+            tokio::select! {
+                joined = watch.next() => match joined {
+                    Some(station) => eprintln!("[Hotspot] {} joined the network.", station),
+                    None => break,
+                },
+                _ = &mut stop => break,
+            }
+        }
+        watch.close().await;
+    });
+    stop_tx
+}
 
-    match output {
-        Ok(o) if o.status.success() => true,
-        _ => false,
+/// Undoes what fling did to the network, on either side: removes the P2P
+/// group, or deletes fling's profile and reactivates the connection it
+/// replaced. Anything fling did not touch is left alone.
+pub async fn cleanup_wifi() {
+    if let Some(stop) = STATIONS.lock().unwrap().take() {
+        let _ = stop.send(());
+    }
+    CHANGES.lock().unwrap().hotspot = None;
+
+    // A P2P group never touched the station connection; removing it is all.
+    if tokio::task::spawn_blocking(p2p::stop_group).await.unwrap_or(false) {
+        eprintln!("[Cleanup] Wi-Fi Direct group removed.");
+        return;
+    }
+
+    let (previous, created) = {
+        let mut changes = CHANGES.lock().unwrap();
+        (changes.previous.take(), std::mem::take(&mut changes.created))
+    };
+    if !created && previous.is_none() {
+        return;
+    }
+    let nm = match NetworkManager::get().await {
+        Ok(nm) => nm,
+        Err(e) => {
+            eprintln!("[Cleanup] {}", e);
+            return;
+        }
+    };
+    if created {
+        delete_profile(nm).await;
+    }
+    if let Some((iface, connection)) = previous {
+        let restored = match nm.wifi_device(&iface).await {
+            Ok(device) => nm.activate(&connection, &device).await,
+            Err(e) => Err(e),
+        };
+        match restored {
+            Ok(()) => eprintln!("[Cleanup] Previous connection restored."),
+            Err(e) => eprintln!("[Cleanup] Could not restore the previous connection: {}", e),
+        }
+    } else if created {
+        eprintln!("[Cleanup] Removed fling's network profile.");
     }
 }
 
+/// Joins a Full AP network controlled by sender. The sender may still be
+/// trying the LAN or bringing the hotspot up, so a missing network is retried.
+/// The connection this replaces is restored by `cleanup_wifi`. `iface`
+/// overrides the detected Wi-Fi interface. Returns the sender's address,
+/// from the lease its DHCP server handed out.
+pub async fn join_wifi_direct_network(
+    iface: Option<&str>,
+    ssid: &str,
+    password: &str,
+) -> Result<Ipv4Addr, String> {
+    let nm = NetworkManager::get().await.map_err(|e| e.to_string())?;
+    let device = nm.choose_device(iface, false).await.map_err(|e| e.to_string())?;
+    remember_previous(nm, &device).await;
+    CHANGES.lock().unwrap().created = true;
+
+    for attempt in 1..=JOIN_ATTEMPTS {
+        delete_profile(nm).await;
+        match nm.join(&device, PROFILE, ssid, password).await {
+            Ok(()) => {
+                return match nm.dhcp_server(&device).await {
+                    Ok(Some(sender)) => Ok(sender),
+                    Ok(None) => Err(format!("'{}' did not say where the sender is", ssid)),
+                    Err(e) => Err(format!("Cannot read the lease on '{}': {}", ssid, e)),
+                };
+            }
+            Err(
+                e @ (NmError::SsidNotFound(_) | NmError::Timeout(_) | NmError::Activation { .. }),
+            ) => {
+                eprintln!("[JoiningNetwork] Attempt {}/{}: {}", attempt, JOIN_ATTEMPTS, e);
+            }
+            Err(e) => return Err(format!("Failed to join '{}': {}", ssid, e)),
+        }
 
+        if let Err(e) = nm.request_scan(&device).await {
+            eprintln!("[JoiningNetwork] Rescan refused: {}", e);
+        }
+        sleep(Duration::from_secs(3)).await;
+    }
+    Err(format!("'{}' did not come up after {} attempts", ssid, JOIN_ATTEMPTS))
+}
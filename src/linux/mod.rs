@@ -2,4 +2,8 @@
 
 pub mod transfer;
 pub mod bluetooth;
-pub mod connection;
\ No newline at end of file
+pub mod connection;
+pub mod nm;
+pub mod p2p;
+pub mod radio;
+pub mod backend;
\ No newline at end of file
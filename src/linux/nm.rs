@@ -0,0 +1,536 @@
+//! A NetworkManager client over D-Bus.
+//!
+//! The hotspot and the receiver's connection are added as settings
+//! dictionaries, activation is followed through the device's `StateChanged`
+//! signal, and a station joining the hotspot is reported by wpa_supplicant,
+//! which runs the AP for NetworkManager. Failures come back as an
+//! [`NmError`] that says what went wrong.
+
+use dbus::arg::{PropMap, RefArg, Variant};
+use dbus::message::MatchRule;
+use dbus::nonblock::stdintf::org_freedesktop_dbus::Properties;
+use dbus::nonblock::{MsgMatch, Proxy, SyncConnection};
+use dbus::{Message, Path};
+use futures::StreamExt;
+use futures::channel::mpsc::UnboundedReceiver;
+use std::collections::HashMap;
+use std::fmt;
+use std::net::Ipv4Addr;
+use std::sync::Arc;
+use std::time::Duration;
+use tokio::sync::OnceCell;
+use tokio::time::timeout;
+
+const NM: &str = "org.freedesktop.NetworkManager";
+const NM_PATH: &str = "/org/freedesktop/NetworkManager";
+const SETTINGS_PATH: &str = "/org/freedesktop/NetworkManager/Settings";
+const SETTINGS: &str = "org.freedesktop.NetworkManager.Settings";
+const SETTINGS_CONNECTION: &str = "org.freedesktop.NetworkManager.Settings.Connection";
+const DEVICE: &str = "org.freedesktop.NetworkManager.Device";
+const WIRELESS: &str = "org.freedesktop.NetworkManager.Device.Wireless";
+const ACTIVE: &str = "org.freedesktop.NetworkManager.Connection.Active";
+const IP4_CONFIG: &str = "org.freedesktop.NetworkManager.IP4Config";
+const DHCP4_CONFIG: &str = "org.freedesktop.NetworkManager.DHCP4Config";
+const SUPPLICANT: &str = "fi.w1.wpa_supplicant1";
+const SUPPLICANT_INTERFACE: &str = "fi.w1.wpa_supplicant1.Interface";
+
+const CALL_TIMEOUT: Duration = Duration::from_secs(10);
+/// How long a connection may take to come up, scan included.
+const ACTIVATION_TIMEOUT: Duration = Duration::from_secs(30);
+
+/// `NM_DEVICE_TYPE_WIFI`.
+const DEVICE_TYPE_WIFI: u32 = 2;
+/// `NMDeviceWifiCapabilities` bits.
+const WIFI_CAP_AP: u32 = 0x40;
+const WIFI_CAP_FREQ_VALID: u32 = 0x100;
+const WIFI_CAP_FREQ_2GHZ: u32 = 0x200;
+const WIFI_CAP_FREQ_5GHZ: u32 = 0x400;
+/// `NMDeviceState` values activation ends in.
+const STATE_ACTIVATED: u32 = 100;
+const STATE_FAILED: u32 = 120;
+/// `NMDeviceStateReason` values worth telling apart.
+const REASON_NO_SECRETS: u32 = 7;
+const REASON_SUPPLICANT_CONFIG_FAILED: u32 = 9;
+const REASON_SUPPLICANT_FAILED: u32 = 10;
+const REASON_SUPPLICANT_TIMEOUT: u32 = 11;
+const REASON_SSID_NOT_FOUND: u32 = 53;
+
+/// The one system bus connection, shared by setup, Ctrl+C and cleanup.
+static CLIENT: OnceCell<NetworkManager> = OnceCell::const_new();
+
+/// A Wi-Fi band, as NetworkManager names it in `802-11-wireless.band`.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Band {
+    /// 2.4GHz.
+    Bg,
+    /// 5GHz.
+    A,
+}
+
+impl Band {
+    fn setting(self) -> &'static str {
+        match self {
+            Band::Bg => "bg",
+            Band::A => "a",
+        }
+    }
+}
+
+impl fmt::Display for Band {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.write_str(match self {
+            Band::Bg => "2.4GHz",
+            Band::A => "5GHz",
+        })
+    }
+}
+
+#[derive(Debug)]
+pub enum NmError {
+    /// The system bus or NetworkManager itself is not there.
+    Unavailable(String),
+    /// NetworkManager has no Wi-Fi device by this name, or none at all.
+    NoWifiDevice(Option<String>),
+    /// The device cannot run an access point.
+    NotApCapable(String),
+    /// The hardware has no radio for the band.
+    BandNotSupported { iface: String, band: Band },
+    /// The hardware has the band, but the AP would not start on it; almost
+    /// always the regulatory domain forbidding AP mode on that channel.
+    BandNotAllowed { iface: String, band: Band, channel: u32 },
+    /// No network with this SSID is in range.
+    SsidNotFound(String),
+    /// The network refused the password.
+    BadPassword(String),
+    /// Activation failed for another `NMDeviceStateReason`.
+    Activation { iface: String, reason: u32 },
+    /// Nothing happened within the time allowed.
+    Timeout(&'static str),
+    DBus(dbus::Error),
+}
+
+impl fmt::Display for NmError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            NmError::Unavailable(msg) => write!(f, "NetworkManager is unavailable: {}", msg),
+            NmError::NoWifiDevice(Some(iface)) => write!(f, "{} is not a Wi-Fi device", iface),
+            NmError::NoWifiDevice(None) => f.write_str("no Wi-Fi device found"),
+            NmError::NotApCapable(iface) => write!(f, "{} cannot run an access point", iface),
+            NmError::BandNotSupported { iface, band } => {
+                write!(f, "{} has no {} radio", iface, band)
+            }
+            NmError::BandNotAllowed { iface, band, channel } => write!(
+                f,
+                "{} may not host on {} channel {} here (regulatory domain)",
+                iface, band, channel
+            ),
+            NmError::SsidNotFound(ssid) => write!(f, "network '{}' not found", ssid),
+            NmError::BadPassword(ssid) => write!(f, "'{}' rejected the password", ssid),
+            NmError::Activation { iface, reason } => {
+                write!(f, "activation on {} failed (device state reason {})", iface, reason)
+            }
+            NmError::Timeout(what) => write!(f, "timed out waiting for {}", what),
+            NmError::DBus(e) => write!(f, "D-Bus: {}", e.message().unwrap_or("unknown error")),
+        }
+    }
+}
+
+impl std::error::Error for NmError {}
+
+impl From<dbus::Error> for NmError {
+    fn from(e: dbus::Error) -> Self {
+        match e.name() {
+            Some("org.freedesktop.DBus.Error.ServiceUnknown")
+            | Some("org.freedesktop.DBus.Error.NameHasNoOwner") => {
+                NmError::Unavailable(e.message().unwrap_or("not running").to_string())
+            }
+            _ => NmError::DBus(e),
+        }
+    }
+}
+
+/// A Wi-Fi device as NetworkManager knows it.
+pub struct WifiDevice {
+    pub iface: String,
+    path: Path<'static>,
+    capabilities: u32,
+}
+
+impl WifiDevice {
+    /// Fails unless the device can run an AP on `band`. Drivers that do not
+    /// report their frequencies (`FREQ_VALID` unset) get the benefit of the
+    /// doubt.
+    pub fn check_ap(&self, band: Band) -> Result<(), NmError> {
+        if !self.can_host() {
+            return Err(NmError::NotApCapable(self.iface.clone()));
+        }
+        if !self.supports(band) {
+            return Err(NmError::BandNotSupported { iface: self.iface.clone(), band });
+        }
+        Ok(())
+    }
+
+    pub fn can_host(&self) -> bool {
+        self.capabilities & WIFI_CAP_AP != 0
+    }
+
+    /// Whether the hardware has a radio for `band`, assumed where the
+    /// driver does not say.
+    pub fn supports(&self, band: Band) -> bool {
+        let needed = match band {
+            Band::Bg => WIFI_CAP_FREQ_2GHZ,
+            Band::A => WIFI_CAP_FREQ_5GHZ,
+        };
+        self.capabilities & WIFI_CAP_FREQ_VALID == 0 || self.capabilities & needed != 0
+    }
+}
+
+/// Stations authorized on any AP wpa_supplicant runs, from the moment the
+/// watch started.
+pub struct StationWatch {
+    conn: Arc<SyncConnection>,
+    signal: MsgMatch,
+    joins: UnboundedReceiver<(Message, (String,))>,
+}
+
+impl StationWatch {
+    /// The MAC address of the next station to join, or `None` once the bus
+    /// connection is gone.
+    pub async fn next(&mut self) -> Option<String> {
+        self.joins.next().await.map(|(_, (station,))| station)
+    }
+
+    pub async fn close(self) {
+        let _ = self.conn.remove_match(self.signal.token()).await;
+    }
+}
+
+#[derive(Clone)]
+pub struct NetworkManager {
+    conn: Arc<SyncConnection>,
+}
+
+impl NetworkManager {
+    /// The shared client, connecting to the system bus on first use. Whether
+    /// NetworkManager is running shows on the first call that needs it, so
+    /// the station watch works without it.
+    pub async fn get() -> Result<&'static NetworkManager, NmError> {
+        CLIENT.get_or_try_init(Self::connect).await
+    }
+
+    async fn connect() -> Result<Self, NmError> {
+        let (resource, conn) = dbus_tokio::connection::new_system_sync()
+            .map_err(|e| NmError::Unavailable(e.to_string()))?;
+        tokio::spawn(async move {
+            let e = resource.await;
+            eprintln!("[NetworkManager] Lost the system bus: {}", e);
+        });
+        Ok(Self { conn })
+    }
+
+    fn proxy<'a>(&'a self, path: impl Into<Path<'a>>) -> Proxy<'a, &'a SyncConnection> {
+        Proxy::new(NM, path, CALL_TIMEOUT, &*self.conn)
+    }
+
+    pub async fn wifi_device(&self, iface: &str) -> Result<WifiDevice, NmError> {
+        let (path,): (Path<'static>,) = self
+            .proxy(NM_PATH)
+            .method_call(NM, "GetDeviceByIpIface", (iface,))
+            .await
+            .map_err(|e| match e.name() {
+                Some("org.freedesktop.NetworkManager.UnknownDevice") => {
+                    NmError::NoWifiDevice(Some(iface.to_string()))
+                }
+                _ => e.into(),
+            })?;
+        self.as_wifi_device(path)
+            .await?
+            .ok_or_else(|| NmError::NoWifiDevice(Some(iface.to_string())))
+    }
+
+    /// Every Wi-Fi device NetworkManager manages, in its order.
+    pub async fn wifi_devices(&self) -> Result<Vec<WifiDevice>, NmError> {
+        let (paths,): (Vec<Path<'static>>,) =
+            self.proxy(NM_PATH).method_call(NM, "GetDevices", ()).await?;
+        let mut devices = Vec::new();
+        for path in paths {
+            devices.extend(self.as_wifi_device(path).await?);
+        }
+        Ok(devices)
+    }
+
+    /// `iface` if given, otherwise the first Wi-Fi device, preferring one
+    /// that can host when `host` is set.
+    pub async fn choose_device(
+        &self,
+        iface: Option<&str>,
+        host: bool,
+    ) -> Result<WifiDevice, NmError> {
+        if let Some(iface) = iface {
+            return self.wifi_device(iface).await;
+        }
+        let mut devices = self.wifi_devices().await?;
+        if devices.is_empty() {
+            return Err(NmError::NoWifiDevice(None));
+        }
+        let index =
+            if host { devices.iter().position(WifiDevice::can_host).unwrap_or(0) } else { 0 };
+        Ok(devices.swap_remove(index))
+    }
+
+    async fn as_wifi_device(&self, path: Path<'static>) -> Result<Option<WifiDevice>, NmError> {
+        let proxy = self.proxy(path.clone());
+        let device_type: u32 = proxy.get(DEVICE, "DeviceType").await?;
+        if device_type != DEVICE_TYPE_WIFI {
+            return Ok(None);
+        }
+        let iface: String = proxy.get(DEVICE, "Interface").await?;
+        let capabilities: u32 = proxy.get(WIRELESS, "WirelessCapabilities").await?;
+        Ok(Some(WifiDevice { iface, path, capabilities }))
+    }
+
+    /// The saved connection active on `device`, to reactivate later.
+    pub async fn active_connection(
+        &self,
+        device: &WifiDevice,
+    ) -> Result<Option<Path<'static>>, NmError> {
+        let active: Path<'static> =
+            self.proxy(device.path.clone()).get(DEVICE, "ActiveConnection").await?;
+        if &*active == "/" {
+            return Ok(None);
+        }
+        let settings: Path<'static> = self.proxy(active).get(ACTIVE, "Connection").await?;
+        Ok(Some(settings))
+    }
+
+    /// Reactivates a saved connection on `device`.
+    pub async fn activate(
+        &self,
+        connection: &Path<'static>,
+        device: &WifiDevice,
+    ) -> Result<(), NmError> {
+        let _: (Path<'static>,) = self
+            .proxy(NM_PATH)
+            .method_call(NM, "ActivateConnection", (connection, &device.path, Path::from("/")))
+            .await?;
+        Ok(())
+    }
+
+    /// Deletes every saved connection whose id is `id`. Returns how many.
+    pub async fn delete_connections(&self, id: &str) -> Result<usize, NmError> {
+        let (paths,): (Vec<Path<'static>>,) =
+            self.proxy(SETTINGS_PATH).method_call(SETTINGS, "ListConnections", ()).await?;
+        let mut deleted = 0;
+        for path in paths {
+            let proxy = self.proxy(path);
+            let (settings,): (HashMap<String, PropMap>,) =
+                proxy.method_call(SETTINGS_CONNECTION, "GetSettings", ()).await?;
+            let matches = settings
+                .get("connection")
+                .and_then(|connection| connection.get("id"))
+                .and_then(|value| value.as_str())
+                == Some(id);
+            if matches {
+                let _: () = proxy.method_call(SETTINGS_CONNECTION, "Delete", ()).await?;
+                deleted += 1;
+            }
+        }
+        Ok(deleted)
+    }
+
+    /// Adds a WPA2 access point profile named `id` on `device` and waits
+    /// for it to come up.
+    pub async fn start_hotspot(
+        &self,
+        device: &WifiDevice,
+        id: &str,
+        ssid: &str,
+        password: &str,
+        band: Band,
+        channel: u32,
+    ) -> Result<(), NmError> {
+        device.check_ap(band)?;
+        let mut settings = wifi_settings(id, ssid, password, "ap");
+        let wireless = settings.get_mut("802-11-wireless").unwrap();
+        wireless.insert("band".into(), variant(band.setting().to_string()));
+        wireless.insert("channel".into(), variant(channel));
+        // NetworkManager's own hotspot addressing: 10.42.0.1/24 with DHCP.
+        settings.get_mut("ipv4").unwrap().insert("method".into(), variant("shared".to_string()));
+
+        self.add_and_activate(device, settings).await.map_err(|e| match e {
+            NmError::Activation {
+                reason:
+                    REASON_SUPPLICANT_CONFIG_FAILED
+                    | REASON_SUPPLICANT_FAILED
+                    | REASON_SUPPLICANT_TIMEOUT,
+                ..
+            } if band == Band::A => {
+                NmError::BandNotAllowed { iface: device.iface.clone(), band, channel }
+            }
+            e => e,
+        })
+    }
+
+    /// Adds a profile named `id` that joins `ssid` on `device` and waits for
+    /// it to come up.
+    pub async fn join(
+        &self,
+        device: &WifiDevice,
+        id: &str,
+        ssid: &str,
+        password: &str,
+    ) -> Result<(), NmError> {
+        let mut settings = wifi_settings(id, ssid, password, "infrastructure");
+        settings.get_mut("ipv4").unwrap().insert("method".into(), variant("auto".to_string()));
+
+        self.add_and_activate(device, settings).await.map_err(|e| match e {
+            NmError::Activation { reason: REASON_SSID_NOT_FOUND, .. } => {
+                NmError::SsidNotFound(ssid.to_string())
+            }
+            NmError::Activation { reason: REASON_NO_SECRETS, .. } => {
+                NmError::BadPassword(ssid.to_string())
+            }
+            e => e,
+        })
+    }
+
+    /// `device`'s own IPv4 address, as a hotspot host has it.
+    pub async fn ipv4_address(&self, device: &WifiDevice) -> Result<Option<Ipv4Addr>, NmError> {
+        let config: Path<'static> =
+            self.proxy(device.path.clone()).get(DEVICE, "Ip4Config").await?;
+        if &*config == "/" {
+            return Ok(None);
+        }
+        let addresses: Vec<PropMap> = self.proxy(config).get(IP4_CONFIG, "AddressData").await?;
+        Ok(addresses
+            .iter()
+            .filter_map(|address| address.get("address")?.as_str()?.parse().ok())
+            .next())
+    }
+
+    /// The DHCP server that configured `device`. On a hotspot that is the
+    /// host, whether or not it also offered itself as the gateway, which is
+    /// the fallback where the server identifier is not reported.
+    pub async fn dhcp_server(&self, device: &WifiDevice) -> Result<Option<Ipv4Addr>, NmError> {
+        let proxy = self.proxy(device.path.clone());
+        let dhcp: Path<'static> = proxy.get(DEVICE, "Dhcp4Config").await?;
+        if &*dhcp != "/" {
+            let options: PropMap = self.proxy(dhcp).get(DHCP4_CONFIG, "Options").await?;
+            let server = options
+                .get("dhcp_server_identifier")
+                .and_then(|value| value.as_str())
+                .and_then(|value| value.parse().ok());
+            if server.is_some() {
+                return Ok(server);
+            }
+        }
+
+        let config: Path<'static> = proxy.get(DEVICE, "Ip4Config").await?;
+        if &*config == "/" {
+            return Ok(None);
+        }
+        let gateway: String = self.proxy(config).get(IP4_CONFIG, "Gateway").await?;
+        Ok(gateway.parse().ok())
+    }
+
+    /// Asks `device` to scan now rather than on its own schedule.
+    pub async fn request_scan(&self, device: &WifiDevice) -> Result<(), NmError> {
+        let options: PropMap = HashMap::new();
+        let _: () = self
+            .proxy(device.path.clone())
+            .method_call(WIRELESS, "RequestScan", (options,))
+            .await?;
+        Ok(())
+    }
+
+    /// Starts reporting stations that join any AP wpa_supplicant runs. Start
+    /// it before the AP comes up so an early join is not missed.
+    pub async fn watch_stations(&self) -> Result<StationWatch, NmError> {
+        let rule = MatchRule::new_signal(SUPPLICANT_INTERFACE, "StaAuthorized")
+            .with_sender(SUPPLICANT);
+        let (signal, joins) = self.conn.add_match(rule).await?.stream();
+        Ok(StationWatch { conn: self.conn.clone(), signal, joins })
+    }
+
+    /// Activates `settings` on `device`, following the device's state until
+    /// it is up or has failed.
+    async fn add_and_activate(
+        &self,
+        device: &WifiDevice,
+        settings: HashMap<&'static str, PropMap>,
+    ) -> Result<(), NmError> {
+        // Subscribe first: a quick failure could otherwise come and go
+        // before we listen.
+        let rule =
+            MatchRule::new_signal(DEVICE, "StateChanged").with_path(device.path.clone());
+        let (signal, mut states) = self.conn.add_match(rule).await?.stream::<(u32, u32, u32)>();
+
+        let result = async {
+            let _: (Path<'static>, Path<'static>) = self
+                .proxy(NM_PATH)
+                .method_call(
+                    NM,
+                    "AddAndActivateConnection",
+                    (settings, &device.path, Path::from("/")),
+                )
+                .await?;
+
+            let outcome = timeout(ACTIVATION_TIMEOUT, async {
+                while let Some((_, (new, _old, reason))) = states.next().await {
+                    match new {
+                        STATE_ACTIVATED => return Ok(()),
+                        STATE_FAILED => {
+                            return Err(NmError::Activation { iface: device.iface.clone(), reason });
+                        }
+                        _ => {}
+                    }
+                }
+                Err(NmError::Unavailable("lost the system bus".into()))
+            })
+            .await;
+            outcome.unwrap_or(Err(NmError::Timeout("the connection to come up")))
+        }
+        .await;
+
+        let _ = self.conn.remove_match(signal.token()).await;
+        result
+    }
+}
+
+fn variant<T: RefArg + 'static>(value: T) -> Variant<Box<dyn RefArg>> {
+    Variant(Box::new(value))
+}
+
+/// The settings a WPA2-PSK profile in `mode` needs, never connected to
+/// automatically.
+fn wifi_settings(
+    id: &str,
+    ssid: &str,
+    password: &str,
+    mode: &str,
+) -> HashMap<&'static str, PropMap> {
+    let connection = PropMap::from([
+        ("id".to_string(), variant(id.to_string())),
+        ("type".to_string(), variant("802-11-wireless".to_string())),
+        ("autoconnect".to_string(), variant(false)),
+    ]);
+    let wireless = PropMap::from([
+        ("ssid".to_string(), variant(ssid.as_bytes().to_vec())),
+        ("mode".to_string(), variant(mode.to_string())),
+    ]);
+    let security = PropMap::from([
+        ("key-mgmt".to_string(), variant("wpa-psk".to_string())),
+        ("psk".to_string(), variant(password.to_string())),
+        ("proto".to_string(), variant(vec!["rsn".to_string()])),
+        ("pairwise".to_string(), variant(vec!["ccmp".to_string()])),
+        ("group".to_string(), variant(vec!["ccmp".to_string()])),
+    ]);
+    let ipv6 = PropMap::from([("method".to_string(), variant("ignore".to_string()))]);
+    HashMap::from([
+        ("connection", connection),
+        ("802-11-wireless", wireless),
+        ("802-11-wireless-security", security),
+        ("ipv4", PropMap::new()),
+        ("ipv6", ipv6),
+    ])
+}
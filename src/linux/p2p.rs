@@ -0,0 +1,328 @@
+//! Wi-Fi Direct through wpa_supplicant's control socket.
+//!
+//! The sender starts an autonomous P2P group owner on a virtual interface
+//! (`p2p-wlan0-0`), so the station connection on `wlan0` stays up. The group
+//! is a persistent-group network block with our SSID and passphrase, which a
+//! receiver joins as a plain WPA2 client; `dnsmasq` hands it an address in
+//! the first 10.42.x.0/24 subnet no interface is on yet, the range a
+//! NetworkManager hotspot picks from.
+//!
+//! Everything here blocks, on the control socket's replies and on
+//! `dnsmasq`, so async callers run it with `tokio::task::spawn_blocking`.
+
+use std::io;
+use std::net::{IpAddr, Ipv4Addr};
+use std::os::unix::net::UnixDatagram;
+use std::path::{Path, PathBuf};
+use std::process::{Child, Command, Stdio};
+use std::sync::Mutex;
+use std::sync::atomic::{AtomicU32, Ordering};
+use std::time::{Duration, Instant};
+
+const CTRL_DIRS: [&str; 2] = ["/run/wpa_supplicant", "/var/run/wpa_supplicant"];
+const REPLY_TIMEOUT: Duration = Duration::from_secs(5);
+const GROUP_START_TIMEOUT: Duration = Duration::from_secs(15);
+/// Where to look for `dnsmasq` besides `PATH`, which often leaves out sbin.
+const SBIN_DIRS: [&str; 2] = ["/usr/sbin", "/sbin"];
+
+/// The group this process started, so Ctrl+C can take it down too.
+static ACTIVE_GROUP: Mutex<Option<P2pGroup>> = Mutex::new(None);
+
+/// A connection to one wpa_supplicant control socket.
+struct WpaCtrl {
+    socket: UnixDatagram,
+    local: PathBuf,
+}
+
+impl WpaCtrl {
+    fn open(remote: &Path) -> io::Result<Self> {
+        static NEXT: AtomicU32 = AtomicU32::new(0);
+        let local = std::env::temp_dir().join(format!(
+            "fling-wpa-{}-{}",
+            std::process::id(),
+            NEXT.fetch_add(1, Ordering::Relaxed)
+        ));
+        let _ = std::fs::remove_file(&local);
+        let socket = UnixDatagram::bind(&local)?;
+        let ctrl = Self { socket, local };
+        ctrl.socket.connect(remote)?;
+        ctrl.socket.set_read_timeout(Some(REPLY_TIMEOUT))?;
+        Ok(ctrl)
+    }
+
+    /// Sends `command` and returns the reply, skipping any unsolicited
+    /// events in between.
+    fn request(&self, command: &str) -> Result<String, String> {
+        self.socket
+            .send(command.as_bytes())
+            .map_err(|e| format!("wpa_supplicant: {}: {}", command, e))?;
+        loop {
+            let reply = self.receive().map_err(|e| format!("wpa_supplicant: {}: {}", command, e))?;
+            if !reply.starts_with('<') {
+                return Ok(reply);
+            }
+        }
+    }
+
+    /// Sends `command`, which must answer `OK`.
+    fn expect_ok(&self, command: &str) -> Result<(), String> {
+        match self.request(command)?.trim() {
+            "OK" => Ok(()),
+            reply => Err(format!("wpa_supplicant rejected '{}': {}", command, reply)),
+        }
+    }
+
+    fn receive(&self) -> io::Result<String> {
+        let mut buf = [0u8; 4096];
+        let len = self.socket.recv(&mut buf)?;
+        Ok(String::from_utf8_lossy(&buf[..len]).into_owned())
+    }
+}
+
+impl Drop for WpaCtrl {
+    fn drop(&mut self) {
+        let _ = std::fs::remove_file(&self.local);
+    }
+}
+
+/// A running P2P group and what it took to bring up.
+pub struct P2pGroup {
+    ctrl: WpaCtrl,
+    network_id: u32,
+    ifname: Option<String>,
+    dhcp: Option<Child>,
+}
+
+impl P2pGroup {
+    /// Takes everything down, logging whatever is left behind.
+    fn stop(mut self) {
+        if let Some(mut dhcp) = self.dhcp.take()
+            && let Err(e) = dhcp.kill().and_then(|_| dhcp.wait())
+        {
+            eprintln!("[Cleanup] Cannot stop dnsmasq (pid {}): {}", dhcp.id(), e);
+        }
+        if let Some(ifname) = &self.ifname
+            && let Err(e) = self.ctrl.expect_ok(&format!("P2P_GROUP_REMOVE {}", ifname))
+        {
+            eprintln!("[Cleanup] Cannot remove the P2P group on {}: {}", ifname, e);
+        }
+        if let Err(e) = self.ctrl.expect_ok(&format!("REMOVE_NETWORK {}", self.network_id)) {
+            eprintln!("[Cleanup] Cannot remove the P2P group's network: {}", e);
+        }
+    }
+}
+
+/// The control socket that takes P2P commands for `iface`: the dedicated
+/// P2P device interface where wpa_supplicant has one, else `iface` itself.
+fn control_socket(iface: &str) -> Option<PathBuf> {
+    let names = [format!("p2p-dev-{}", iface), iface.to_string()];
+    CTRL_DIRS
+        .iter()
+        .flat_map(|dir| names.iter().map(move |name| Path::new(dir).join(name)))
+        .find(|path| path.exists())
+}
+
+/// `dnsmasq` on `PATH` or in an sbin directory.
+fn find_dnsmasq() -> Option<PathBuf> {
+    let path = std::env::var_os("PATH").unwrap_or_default();
+    std::env::split_paths(&path)
+        .chain(SBIN_DIRS.iter().map(PathBuf::from))
+        .map(|dir| dir.join("dnsmasq"))
+        .find(|candidate| candidate.is_file())
+}
+
+/// The first 10.42.x.0/24 that overlaps no network this machine is on.
+fn free_subnet() -> Result<Ipv4Addr, String> {
+    let interfaces =
+        if_addrs::get_if_addrs().map_err(|e| format!("Cannot list network interfaces: {}", e))?;
+    let networks: Vec<(u32, u32)> = interfaces
+        .iter()
+        .filter_map(|interface| match &interface.addr {
+            if_addrs::IfAddr::V4(v4) => Some((u32::from(v4.ip), u32::from(v4.netmask))),
+            _ => None,
+        })
+        .collect();
+    (0..=255u8)
+        .map(|n| Ipv4Addr::new(10, 42, n, 0))
+        .find(|subnet| {
+            networks.iter().all(|&(ip, netmask)| {
+                // The wider of the two masks decides whether they overlap.
+                let mask = netmask & 0xffff_ff00;
+                ip & mask != u32::from(*subnet) & mask
+            })
+        })
+        .ok_or_else(|| "every 10.42.x.0/24 subnet is already in use".to_string())
+}
+
+/// The IPv4 address `ifname` ended up with.
+fn assigned_address(ifname: &str) -> Option<Ipv4Addr> {
+    if_addrs::get_if_addrs()
+        .ok()?
+        .into_iter()
+        .filter(|interface| interface.name == ifname)
+        .find_map(|interface| match interface.ip() {
+            IpAddr::V4(v4) => Some(v4),
+            IpAddr::V6(_) => None,
+        })
+}
+
+/// Starts a group owner on a virtual interface next to `iface`, with `ssid`
+/// and `password` on `freq` MHz, and serves DHCP on it. Returns the group's
+/// interface and this machine's address on it.
+///
+/// Everything the group needs is checked before wpa_supplicant is asked
+/// for anything, so a missing piece leaves the interface as it was.
+pub fn start_group(
+    iface: &str,
+    ssid: &str,
+    password: &str,
+    freq: u32,
+) -> Result<(String, Ipv4Addr), String> {
+    let dnsmasq = find_dnsmasq()
+        .ok_or("dnsmasq is not installed, and a P2P group needs it to serve DHCP")?;
+    let subnet = free_subnet()?;
+    let path = control_socket(iface)
+        .ok_or_else(|| format!("no wpa_supplicant control socket for {}", iface))?;
+    let ctrl = WpaCtrl::open(&path).map_err(|e| format!("{}: {}", path.display(), e))?;
+    let monitor = WpaCtrl::open(&path).map_err(|e| format!("{}: {}", path.display(), e))?;
+    monitor.expect_ok("ATTACH")?;
+
+    let capabilities = ctrl.request("GET_CAPABILITY modes")?;
+    if !capabilities.split_whitespace().any(|mode| mode == "P2P") {
+        return Err(format!("wpa_supplicant on {} has no P2P support", iface));
+    }
+
+    let network_id = ctrl
+        .request("ADD_NETWORK")?
+        .trim()
+        .parse::<u32>()
+        .map_err(|_| "wpa_supplicant did not add the group network".to_string())?;
+    let mut group = P2pGroup { ctrl, network_id, ifname: None, dhcp: None };
+
+    let network = Network { ssid, password, freq, subnet, dnsmasq: &dnsmasq };
+    match bring_up(&mut group, &monitor, &network) {
+        Ok(started) => {
+            *ACTIVE_GROUP.lock().unwrap() = Some(group);
+            Ok(started)
+        }
+        Err(e) => {
+            group.stop();
+            Err(e)
+        }
+    }
+}
+
+/// What the group is brought up with.
+struct Network<'a> {
+    ssid: &'a str,
+    password: &'a str,
+    freq: u32,
+    /// The /24 to address the group in, as `10.42.x.0`.
+    subnet: Ipv4Addr,
+    dnsmasq: &'a Path,
+}
+
+fn bring_up(
+    group: &mut P2pGroup,
+    monitor: &WpaCtrl,
+    network: &Network,
+) -> Result<(String, Ipv4Addr), String> {
+    let id = group.network_id;
+    let settings = [
+        ("mode", "3".to_string()),
+        ("disabled", "2".to_string()),
+        ("ssid", format!("\"{}\"", network.ssid)),
+        ("psk", format!("\"{}\"", network.password)),
+        ("key_mgmt", "WPA-PSK".to_string()),
+        ("proto", "RSN".to_string()),
+        ("pairwise", "CCMP".to_string()),
+    ];
+    for (key, value) in settings {
+        group.ctrl.expect_ok(&format!("SET_NETWORK {} {} {}", id, key, value))?;
+    }
+    group
+        .ctrl
+        .expect_ok(&format!("P2P_GROUP_ADD persistent={} freq={}", id, network.freq))?;
+
+    let ifname = wait_for_group(monitor)?;
+    eprintln!("[Hotspot] P2P group started on {}", ifname);
+    group.ifname = Some(ifname.clone());
+
+    let [a, b, c, _] = network.subnet.octets();
+    let gateway = Ipv4Addr::new(a, b, c, 1);
+    let output = Command::new("ip")
+        .args(["addr", "add", &format!("{}/24", gateway), "dev", &ifname])
+        .output()
+        .map_err(|e| format!("Failed to run ip: {}", e))?;
+    if !output.status.success() {
+        return Err(format!(
+            "Cannot address {}: {}",
+            ifname,
+            String::from_utf8_lossy(&output.stderr).trim()
+        ));
+    }
+
+    let address = assigned_address(&ifname)
+        .ok_or_else(|| format!("{} has no IPv4 address after adding {}", ifname, gateway))?;
+
+    let range = format!(
+        "--dhcp-range={},{},255.255.255.0,1h",
+        Ipv4Addr::new(a, b, c, 10),
+        Ipv4Addr::new(a, b, c, 254)
+    );
+    let dhcp = Command::new(network.dnsmasq)
+        .args([
+            "--keep-in-foreground",
+            "--conf-file=/dev/null",
+            "--port=0",
+            "--bind-interfaces",
+            "--except-interface=lo",
+            &format!("--interface={}", ifname),
+            &range,
+            // No default route or DNS: the receiver only needs to reach us.
+            "--dhcp-option=3",
+            "--dhcp-option=6",
+        ])
+        .stdout(Stdio::null())
+        .stderr(Stdio::null())
+        .spawn()
+        .map_err(|e| format!("Cannot start dnsmasq for DHCP: {}", e))?;
+    group.dhcp = Some(dhcp);
+    Ok((ifname, address))
+}
+
+/// Waits for `P2P-GROUP-STARTED <ifname> GO ...` and returns the interface.
+fn wait_for_group(monitor: &WpaCtrl) -> Result<String, String> {
+    let deadline = Instant::now() + GROUP_START_TIMEOUT;
+    while Instant::now() < deadline {
+        let event = match monitor.receive() {
+            Ok(event) => event,
+            Err(e) if matches!(e.kind(), io::ErrorKind::WouldBlock | io::ErrorKind::TimedOut) => {
+                continue;
+            }
+            Err(e) => return Err(format!("wpa_supplicant: {}", e)),
+        };
+        // Events look like `<3>P2P-GROUP-STARTED p2p-wlan0-0 GO ssid=...`.
+        let body = event.split_once('>').map_or(event.as_str(), |(_, body)| body);
+        let mut words = body.split_whitespace();
+        match (words.next(), words.next(), words.next()) {
+            (Some("P2P-GROUP-STARTED"), Some(ifname), Some("GO")) => return Ok(ifname.to_string()),
+            (Some(failure @ ("P2P-GROUP-FORMATION-FAILURE" | "P2P-GO-NEG-FAILURE")), ..) => {
+                return Err(failure.to_string());
+            }
+            _ => {}
+        }
+    }
+    Err("P2P group did not start in time".into())
+}
+
+/// Takes down the group this process started. Returns whether there was one.
+pub fn stop_group() -> bool {
+    match ACTIVE_GROUP.lock().unwrap().take() {
+        Some(group) => {
+            group.stop();
+            true
+        }
+        None => false,
+    }
+}
@@ -0,0 +1,113 @@
+//! Which channel the sender's network goes on.
+//!
+//! The hardware capabilities NetworkManager reports say nothing about the
+//! regulatory domain, so the channel list comes from `iw`. A channel
+//! flagged disabled, no-IR (no initiating radiation, the older "passive
+//! scanning") or radar detection cannot host an access point.
+
+use std::process::Command;
+
+use crate::linux::nm::{Band, WifiDevice};
+
+/// 5GHz channels to try first, in order. The upper block is allowed for
+/// APs in most places; the lower one covers much of the rest.
+const PREFERRED_5GHZ: [u32; 9] = [149, 153, 157, 161, 165, 36, 40, 44, 48];
+/// The non-overlapping 2.4GHz channels, 6 first as it is legal everywhere.
+const PREFERRED_2GHZ: [u32; 3] = [6, 1, 11];
+
+/// One channel from `iw phy ... info`.
+#[derive(Debug, Clone, Copy)]
+pub struct Channel {
+    pub number: u32,
+    /// Whether the regulatory domain lets this machine start a network here.
+    pub can_host: bool,
+}
+
+/// Whether this machine has any wireless interface to join a network with.
+pub fn has_wireless_interface() -> bool {
+    let Ok(entries) = std::fs::read_dir("/sys/class/net") else {
+        return false;
+    };
+    entries.flatten().any(|entry| entry.path().join("phy80211").exists())
+}
+
+pub fn band_of(channel: u32) -> Band {
+    if channel <= 14 { Band::Bg } else { Band::A }
+}
+
+/// The centre frequency of `channel` in MHz.
+pub fn frequency(channel: u32) -> u32 {
+    match channel {
+        14 => 2484,
+        1..=13 => 2407 + 5 * channel,
+        _ => 5000 + 5 * channel,
+    }
+}
+
+/// The channels `iface`'s radio has, or `None` when that cannot be read
+/// (no `iw`, or not a cfg80211 driver).
+pub fn channels(iface: &str) -> Option<Vec<Channel>> {
+    let phy = std::fs::read_to_string(format!("/sys/class/net/{}/phy80211/name", iface)).ok()?;
+    let output = Command::new("iw").args(["phy", phy.trim(), "info"]).output().ok()?;
+    if !output.status.success() {
+        return None;
+    }
+    Some(String::from_utf8_lossy(&output.stdout).lines().filter_map(parse_channel).collect())
+}
+
+/// Parses a frequency line such as `* 5745.0 MHz [149] (30.0 dBm)` or
+/// `* 5260 MHz [52] (20.0 dBm) (no IR, radar detection)`.
+fn parse_channel(line: &str) -> Option<Channel> {
+    let line = line.trim().strip_prefix("* ")?;
+    if !line.contains(" MHz [") {
+        return None;
+    }
+    let (_, rest) = line.split_once('[')?;
+    let (number, flags) = rest.split_once(']')?;
+    let number = number.parse().ok()?;
+    let restricted = ["disabled", "no IR", "passive scan", "no IBSS", "radar detection"]
+        .iter()
+        .any(|flag| flags.contains(flag));
+    Some(Channel { number, can_host: !restricted })
+}
+
+/// Picks the channel to host on: `requested` if given, otherwise the first
+/// preferred 5GHz channel the device and the regulatory domain allow, then
+/// the same on 2.4GHz. Without a channel list, 2.4GHz channel 6 is the one
+/// choice that is legal everywhere.
+pub fn choose_channel(device: &WifiDevice, requested: Option<u32>) -> u32 {
+    let available = channels(&device.iface);
+
+    if let Some(channel) = requested {
+        let allowed = available
+            .as_ref()
+            .map(|list| list.iter().any(|c| c.number == channel && c.can_host));
+        if allowed == Some(false) {
+            eprintln!(
+                "[Hotspot] Channel {} looks unavailable for hosting on {}; trying it anyway.",
+                channel, device.iface
+            );
+        }
+        return channel;
+    }
+
+    let Some(available) = available else {
+        eprintln!("[Hotspot] Cannot read {}'s channel list; using 2.4GHz channel 6.", device.iface);
+        return 6;
+    };
+    let can_host = |channel: &u32| available.iter().any(|c| c.number == *channel && c.can_host);
+    let five = device
+        .supports(Band::A)
+        .then(|| PREFERRED_5GHZ.into_iter().find(can_host))
+        .flatten();
+    match five.or_else(|| PREFERRED_2GHZ.into_iter().find(can_host)) {
+        Some(channel) => channel,
+        None => {
+            eprintln!(
+                "[Hotspot] No channel on {} is clear for hosting; trying channel 6.",
+                device.iface
+            );
+            6
+        }
+    }
+}
@@ -0,0 +1,145 @@
+use crate::macos::bluetooth::{PendingPairing, get_bluetooth_mac, start_key_exchange};
+use crate::macos::{connection, transfer};
+use crate::platform::mdns::{Mdns, WithMdns};
+use crate::platform::terminal::Terminal;
+use crate::platform::{
+    Backends, DeviceInfo, Discovery, KeyExchange, LinkOptions, LinkProvider, Listening, Sighting,
+    Transport,
+};
+use crate::tunnel::transfer::{Destination, Offer, Payload, TransferError};
+use std::net::{IpAddr, SocketAddr};
+use std::process::Command;
+use std::time::Duration;
+use tokio::sync::mpsc;
+
+const SENDING_UNSUPPORTED: &str =
+    "Sending from a Mac is not currently supported. See README.md for more details.";
+
+/// CoreBluetooth, through `bluest`. Only the receiving side is implemented.
+#[derive(Clone)]
+pub struct CoreBluetooth;
+
+impl Discovery for CoreBluetooth {
+    async fn scan(&self, _found: mpsc::UnboundedSender<Sighting>) -> Result<(), String> {
+        Err(SENDING_UNSUPPORTED.into())
+    }
+
+    fn local_name(&self) -> String {
+        let output = Command::new("scutil")
+            .args(["--get", "ComputerName"])
+            .output();
+        match output {
+            Ok(output) if output.status.success() => {
+                String::from_utf8_lossy(&output.stdout).trim().to_string()
+            }
+            Ok(output) => {
+                let err_m = String::from_utf8_lossy(&output.stderr).trim().to_string();
+                eprintln!("Failed to get computer name: {}", err_m);
+                "unknown".to_string()
+            }
+            Err(e) => {
+                eprintln!("Failed to get computer name: {}", e);
+                "unknown".to_string()
+            }
+        }
+    }
+
+    fn local_address(&self) -> Option<String> {
+        get_bluetooth_mac()
+    }
+}
+
+impl KeyExchange for CoreBluetooth {
+    type Pairing = PendingPairing;
+
+    async fn offer(&self, _peer: &DeviceInfo, _offer: &Offer) -> Result<Option<Vec<u8>>, String> {
+        Err(SENDING_UNSUPPORTED.into())
+    }
+
+    async fn accept(&self) -> Result<PendingPairing, String> {
+        // CoreBluetooth through bluest is central-only, so senders cannot
+        // list this Mac over Bluetooth; they find it over mDNS instead.
+        start_key_exchange().await.map_err(|e| e.to_string())
+    }
+}
+
+/// Joins the sender's hotspot with `networksetup`.
+pub struct NetworksetupLink {
+    options: LinkOptions,
+}
+
+impl LinkProvider for NetworksetupLink {
+    async fn host(&self, _ssid: &str, _password: &str) -> Result<IpAddr, String> {
+        Err(SENDING_UNSUPPORTED.into())
+    }
+
+    async fn join(&self, ssid: &str, password: &str) -> Result<IpAddr, String> {
+        let iface = self.options.iface.as_deref();
+        match connection::join_wifi_direct_network(iface, ssid, password) {
+            Some(sender) => Ok(IpAddr::V4(sender)),
+            None => Err(format!("Failed to join '{}'", ssid)),
+        }
+    }
+
+    async fn teardown(&self) {
+        connection::cleanup_wifi().await;
+    }
+}
+
+/// The encrypted session over TCP, on the LAN or the hotspot.
+pub struct TcpTransport;
+
+impl Transport for TcpTransport {
+    async fn listen(&self) -> Result<Listening, String> {
+        Err(SENDING_UNSUPPORTED.into())
+    }
+
+    async fn listen_on(&self, _ip: IpAddr) -> Result<(), String> {
+        Err(SENDING_UNSUPPORTED.into())
+    }
+
+    async fn accept(&self, _key: &[u8], _wait: Duration) -> Result<SocketAddr, TransferError> {
+        Err(TransferError::Fatal(SENDING_UNSUPPORTED.into()))
+    }
+
+    async fn send(&self, _payload: &mut Payload) -> Result<(), TransferError> {
+        Err(TransferError::Fatal(SENDING_UNSUPPORTED.into()))
+    }
+
+    async fn receive(
+        &self,
+        peers: &[SocketAddr],
+        wait: Duration,
+        destination: &Destination,
+        key: &[u8],
+    ) -> Result<(), TransferError> {
+        transfer::receive_file(peers, wait, destination, key).await
+    }
+}
+
+pub type NativeBackends = Backends<
+    WithMdns<CoreBluetooth>,
+    WithMdns<CoreBluetooth>,
+    NetworksetupLink,
+    TcpTransport,
+    Terminal,
+>;
+
+/// CoreBluetooth, with mDNS beside it for senders on the same network.
+pub async fn native(link: LinkOptions) -> Result<NativeBackends, String> {
+    let mdns = match Mdns::new() {
+        Ok(mdns) => Some(mdns),
+        Err(e) => {
+            eprintln!("[mDNS] Unavailable ({}).", e);
+            None
+        }
+    };
+    let discovery = WithMdns { radio: Some(CoreBluetooth), mdns };
+    Ok(Backends {
+        discovery: discovery.clone(),
+        key_exchange: discovery,
+        link: NetworksetupLink { options: link },
+        transport: TcpTransport,
+        ui: Terminal,
+    })
+}
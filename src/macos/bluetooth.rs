@@ -1,8 +1,11 @@
-use std::{process::Command, time::Duration};
+use std::{fmt, process::Command, time::Duration};
 use anyhow::Result;
-use bluest::Adapter;
+use bluest::{Adapter, Characteristic, Device};
 use futures_lite::StreamExt;
-use uuid::Uuid;
+use crate::crypto::handshake::{Handshake, Role, SessionKeys, Verdict};
+use crate::crypto::stream::open_message;
+use crate::platform::{Pairing, gatt};
+use crate::tunnel::transfer::Offer;
 
 pub fn get_bluetooth_mac() -> Option<String> {
     let output = Command::new("system_profiler")
@@ -18,7 +21,45 @@ pub fn get_bluetooth_mac() -> Option<String> {
             .map(|s| s.trim().to_string())
     })
 }
-pub async fn receive_fling_key() -> Result<Vec<u8>> {
+/// A key exchange that has finished on the wire but still waits for the user
+/// to compare the verification code.
+pub struct PendingPairing {
+    adapter: Adapter,
+    device: Device,
+    confirm_char: Characteristic,
+    keys: SessionKeys,
+    offer: Offer,
+}
+
+impl fmt::Debug for PendingPairing {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "PendingPairing({:?})", self.device.id())
+    }
+}
+
+impl Pairing for PendingPairing {
+    fn sas(&self) -> &str {
+        &self.keys.sas
+    }
+
+    fn offer(&self) -> &Offer {
+        &self.offer
+    }
+
+    async fn respond(self, verdict: Verdict) -> std::result::Result<Vec<u8>, String> {
+        let result = self.confirm_char.write(&[verdict.to_byte()]).await;
+        let _ = self.adapter.disconnect_device(&self.device).await;
+        result.map_err(|e| format!("Failed to send verdict: {}", e))?;
+
+        match verdict {
+            Verdict::Accept => Ok(self.keys.key),
+            Verdict::Decline => Err("Transfer declined".into()),
+            Verdict::CodeMismatch => Err("Verification code rejected".into()),
+        }
+    }
+}
+
+pub async fn start_key_exchange() -> Result<PendingPairing> {
 
     let adapter = Adapter::default()
         .await
@@ -26,13 +67,12 @@ pub async fn receive_fling_key() -> Result<Vec<u8>> {
     adapter.wait_available().await?;
 
     let mut scan = adapter.scan(&[]).await?;
-    let service_uuid = Uuid::parse_str("12345678-1234-5678-1234-56789abcdef0")?;
 
-    println!("Scanning for fling sender...");
+    eprintln!("Scanning for fling sender...");
     let device = loop {
         if let Some(discovered) = scan.next().await {
-            if discovered.adv_data.services.contains(&service_uuid) {
-                println!("Found fling sender!");
+            if discovered.adv_data.services.contains(&gatt::SERVICE) {
+                eprintln!("Found fling sender!");
                 break discovered.device;
             }
         }
@@ -58,32 +98,86 @@ pub async fn receive_fling_key() -> Result<Vec<u8>> {
     tokio::time::sleep(Duration::from_millis(500)).await;
 
     let services = device.services().await?;
-    let char_uuid = Uuid::parse_str("abcdef12-3456-7890-abcd-ef1234567890")?;
-    let mut fling_char = None;
+    let mut kx_char = None;
+    let mut confirm_char = None;
+    let mut offer_char = None;
+    let mut version_char = None;
+    let mut identity_char = None;
 
     for svc in services {
-        let svc_uuid = svc.uuid();
+        if svc.uuid() != gatt::SERVICE {
+            continue;
+        }
         let _ = svc.discover_characteristics().await;
-        let characteristics = svc.characteristics().await?;
-        for ch in characteristics { 
-            let ch_uuid = ch.uuid();
-            if ch_uuid == char_uuid {
-                println!("Found fling char.!");
-                fling_char = Some(ch);
-                break;
+        for ch in svc.characteristics().await? {
+            if ch.uuid() == gatt::VERSION {
+                version_char = Some(ch);
+            } else if ch.uuid() == gatt::IDENTITY {
+                identity_char = Some(ch);
+            } else if ch.uuid() == gatt::KEY_EXCHANGE {
+                kx_char = Some(ch);
+            } else if ch.uuid() == gatt::CONFIRM {
+                confirm_char = Some(ch);
+            } else if ch.uuid() == gatt::OFFER {
+                offer_char = Some(ch);
             }
         }
-        if svc_uuid == service_uuid && fling_char.is_none() {
-            println!("Found fling svc, but char. was not found in it");
+    }
+    let (kx_char, confirm_char, offer_char) = match (kx_char, confirm_char, offer_char) {
+        (Some(kx), Some(confirm), Some(offer)) => (kx, confirm, offer),
+        _ => {
+            let _ = adapter.disconnect_device(&device).await;
+            return Err(anyhow::anyhow!("Fling key exchange characteristics not found"));
         }
+    };
+
+    let version = match version_char {
+        Some(ch) => ch.read().await?,
+        None => Vec::new(),
+    };
+    if let Err(e) = gatt::check_version(&version) {
+        let _ = adapter.disconnect_device(&device).await;
+        return Err(anyhow::anyhow!(e));
+    }
+    if let Some(ch) = identity_char {
+        eprintln!("[Bluetooth][macOS] Sender: {}", gatt::parse_identity(&ch.read().await?));
     }
-    let characteristic =
-        fling_char.ok_or_else(|| anyhow::anyhow!("Fling characteristic not found"))?;
 
+    eprintln!("[Bluetooth][macOS] Starting key exchange...");
+    let sender_public = {
+        let mut notifications = kx_char.notify().await?;
+        match tokio::time::timeout(Duration::from_secs(10), notifications.next()).await {
+            Ok(Some(value)) => value?,
+            _ => {
+                let _ = adapter.disconnect_device(&device).await;
+                return Err(anyhow::anyhow!("Sender did not send its public key"));
+            }
+        }
+    };
 
-    let key = characteristic.read().await?;
-    println!("[Bluetooth][macOS] Fling key read ({} bytes)", key.len());
+    let handshake = Handshake::new(Role::Receiver);
+    kx_char.write(&handshake.public_key()).await?;
+    let keys = match handshake.finish(&sender_public) {
+        Ok(keys) => keys,
+        Err(e) => {
+            let _ = adapter.disconnect_device(&device).await;
+            return Err(anyhow::anyhow!(e));
+        }
+    };
+
+    let offer = match read_offer(&offer_char, &keys.key).await {
+        Ok(offer) => offer,
+        Err(e) => {
+            let _ = adapter.disconnect_device(&device).await;
+            return Err(e);
+        }
+    };
 
-    adapter.disconnect_device(&device.clone()).await?;
-    Ok(key)
-}
\ No newline at end of file
+    Ok(PendingPairing { adapter, device, confirm_char, keys, offer })
+}
+
+async fn read_offer(offer_char: &Characteristic, key: &[u8]) -> Result<Offer> {
+    let sealed = offer_char.read().await?;
+    let offer = open_message(key, &sealed)?;
+    Offer::decode(&offer).map_err(|e| anyhow::anyhow!(e))
+}
@@ -1,41 +1,82 @@
-use std::{net::TcpStream, process::Command, time::Duration};
-use tokio::time::sleep;
+use std::{net::Ipv4Addr, process::Command, sync::Mutex, time::Duration};
 use std::time::Instant;
 use std::thread::sleep as thread_sleep;
 
-pub async fn wait_for_ip() -> Result<(), String> {
-    for _attempt in 1..=30{
-        let output = Command::new("ifconfig")
-            .arg("en0")
-            .output()
-            .map_err(|e| format!("Failed to run ifconfig: {}", e))?;
+/// What joining changed, so cleanup can switch back and forget the
+/// sender's network.
+#[derive(Default)]
+struct Changes {
+    /// The interface that joined.
+    interface: Option<String>,
+    /// The network it was on before.
+    previous: Option<String>,
+    /// The sender's network.
+    joined: Option<String>,
+}
+
+static CHANGES: Mutex<Changes> =
+    Mutex::new(Changes { interface: None, previous: None, joined: None });
 
-        let stdout = String::from_utf8_lossy(&output.stdout);
-        if stdout.contains("inet 10.42.0.") {
-            println!("[Receiver] Got IP on en0");
-            return Ok(());
+/// The Wi-Fi interface, from the "Wi-Fi" (or, on older releases, "AirPort")
+/// hardware port. Falls back to `en0`, which it is on most Macs.
+pub fn wifi_interface() -> String {
+    let output = Command::new("networksetup").arg("-listallhardwareports").output();
+    let listing = match output {
+        Ok(output) if output.status.success() => {
+            String::from_utf8_lossy(&output.stdout).into_owned()
+        }
+        _ => return "en0".to_string(),
+    };
+    // Blocks of `Hardware Port: Wi-Fi` / `Device: en0` / `Ethernet Address: ...`.
+    let mut lines = listing.lines();
+    while let Some(line) = lines.next() {
+        if let Some(port) = line.strip_prefix("Hardware Port: ")
+            && matches!(port.trim(), "Wi-Fi" | "AirPort")
+            && let Some(device) = lines.next().and_then(|l| l.strip_prefix("Device: "))
+        {
+            return device.trim().to_string();
         }
-        sleep(Duration::from_millis(1000)).await;
     }
-    Err("Timed out waiting for IP on en0".to_string())
+    "en0".to_string()
 }
-pub fn wait_for_port(host: &str, port: u16) -> TcpStream {
-    let addr = format!("{}:{}", host, port);
-    loop {
-        match TcpStream::connect(&addr) {
-            Ok(stream) => {
-                println!("[Receiver]✅ Connected to {}", addr);
-                return stream;
-            }
-            Err(_) => {
-                std::io::Write::flush(&mut std::io::stdout()).unwrap();
-                let _ = sleep(Duration::from_millis(500));
-            }
+
+/// The Wi-Fi network `interface` is on, if any.
+fn current_network(interface: &str) -> Option<String> {
+    let output = Command::new("networksetup")
+        .args(["-getairportnetwork", interface])
+        .output()
+        .ok()?;
+    let stdout = String::from_utf8_lossy(&output.stdout);
+    let (_, ssid) = stdout.trim().split_once("Current Wi-Fi Network: ")?;
+    Some(ssid.to_string())
+}
+
+/// The DHCP server that gave `interface` its lease, which on the sender's
+/// network is the sender; the router where no server identifier is known.
+fn dhcp_server(interface: &str) -> Option<Ipv4Addr> {
+    ["server_identifier", "router"].iter().find_map(|option| {
+        let output = Command::new("ipconfig").args(["getoption", interface, option]).output().ok()?;
+        String::from_utf8_lossy(&output.stdout).trim().parse().ok()
+    })
+}
+
+/// Joins the sender's network on `interface`, or the detected Wi-Fi
+/// interface, and returns the sender's address on it.
+pub fn join_wifi_direct_network(
+    interface: Option<&str>,
+    ssid: &str,
+    password: &str,
+) -> Option<Ipv4Addr> {
+    let interface = interface.map_or_else(wifi_interface, str::to_string);
+    let interface = interface.as_str();
+    {
+        let mut changes = CHANGES.lock().unwrap();
+        if changes.joined.is_none() {
+            changes.previous = current_network(interface);
         }
+        changes.interface = Some(interface.to_string());
+        changes.joined = Some(ssid.to_string());
     }
-}
-pub fn join_wifi_direct_network(ssid: &str, password: &str) -> bool {
-    let interface = "en0";
 
     let timeout = Duration::from_secs(20);
     let start = Instant::now();
@@ -47,7 +88,7 @@ pub fn join_wifi_direct_network(ssid: &str, password: &str) -> bool {
 
         match output {
             Ok(output) if output.status.success() => {
-                println!("[Attempt] Command executed successfully.");
+                eprintln!("[Attempt] Command executed successfully.");
             }
             Ok(output) => {
                 eprintln!("[Attempt] Command failed: {}", String::from_utf8_lossy(&output.stderr));
@@ -58,5 +99,30 @@ pub fn join_wifi_direct_network(ssid: &str, password: &str) -> bool {
         }
         thread_sleep(Duration::from_millis(500));
     }
-    true
+    dhcp_server(interface)
+}
+
+/// Forgets the sender's network and rejoins the one the interface was on
+/// before, using the password macOS keeps for it.
+pub async fn cleanup_wifi() {
+    let Changes { interface, previous, joined } = std::mem::take(&mut *CHANGES.lock().unwrap());
+    let (Some(interface), Some(joined)) = (interface, joined) else {
+        return;
+    };
+    let interface = interface.as_str();
+
+    let _ = Command::new("networksetup")
+        .args(["-removepreferredwirelessnetwork", interface, &joined])
+        .output();
+    if let Some(previous) = previous {
+        match Command::new("networksetup")
+            .args(["-setairportnetwork", interface, &previous])
+            .output()
+        {
+            Ok(output) if output.status.success() => {
+                eprintln!("[Cleanup] Rejoined {}.", previous)
+            }
+            _ => eprintln!("[Cleanup] Could not rejoin {}.", previous),
+        }
+    }
 }
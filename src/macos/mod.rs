@@ -1,4 +1,5 @@
 #![cfg(target_os="macos")]
 pub mod transfer;
 pub mod connection;
-pub mod bluetooth;
\ No newline at end of file
+pub mod bluetooth;
+pub mod backend;
\ No newline at end of file
@@ -1,12 +1,6 @@
-mod bluetooth;
-mod fsm;
-mod utils;
 use clap::Parser;
-use utils::cli::{Cli, Commands};
-mod tunnel;
-mod linux;
-mod macos;
-mod crypto;
+use fling::utils::cli::{Cli, Commands};
+use fling::{fsm, platform, tunnel, utils};
 use tokio::signal;
 
 #[tokio::main]
@@ -14,30 +8,76 @@ async fn main() {
 
     tokio::spawn(async move {
         if signal::ctrl_c().await.is_ok() {
-            println!("\n[Signal] Caught Ctrl+C! Cleaning up...");
+            eprintln!("\n[Signal] Caught Ctrl+C! Cleaning up...");
 
             #[cfg(target_os="linux")]
-            linux::connection::cleanup_wifi().await;
+            fling::linux::connection::cleanup_wifi().await;
+            #[cfg(target_os="macos")]
+            fling::macos::connection::cleanup_wifi().await;
             std::process::exit(1)
         }
     });
 
     let cli = Cli::parse();
+    let channel = match &cli.command {
+        Commands::Send { to: None, first: false, .. } if !platform::terminal::can_pick() => {
+            eprintln!("No terminal to pick a receiver on; pass --to <NAME|MAC|ALIAS> or --first.");
+            std::process::exit(1);
+        }
+        Commands::Send { channel, .. } => *channel,
+        Commands::Receive { .. } => None,
+    };
+    let link = platform::LinkOptions { iface: cli.iface, channel };
+    let backends = match platform::native(link).await {
+        Ok(backends) => backends,
+        Err(e) => {
+            eprintln!("{}", e);
+            std::process::exit(1);
+        }
+    };
 
     match cli.command {
-        Commands::Send { filepath } => {
-            println!("Sender Mode Enabled!\nFile to send: {}", filepath);
+        Commands::Send { mut paths, from_list, name, to, first, .. } => {
+            let target = match (to, first) {
+                (Some(to), _) => platform::Target::Named(to),
+                (None, true) => platform::Target::First,
+                (None, false) => platform::Target::Ask,
+            };
+            if let Some(list) = from_list {
+                match utils::cli::read_path_list(&list) {
+                    Ok(listed) => paths.extend(listed),
+                    Err(e) => {
+                        eprintln!("{}", e);
+                        std::process::exit(1);
+                    }
+                }
+            }
+            eprintln!("Sender Mode Enabled!\nPaths to send: {}", paths.join(", "));
             if std::env::consts::OS=="macos" {
-                println!("Sending from a MAC is not currently supported.\nSee README.md for more details.");
+                eprintln!("Sending from a MAC is not currently supported.\nSee README.md for more details.");
                 std::process::exit(1);
         }
 
-            #[cfg(target_os="linux")]
-            fsm::sender_fsm::start_sender_fsm(&filepath).await;
+            let name = name.as_deref();
+            let sent = fsm::sender_fsm::start_sender_fsm(&backends, &paths, name, &target).await;
+            if !matches!(sent, fsm::sender_fsm::SenderState::SendSuccess) {
+                std::process::exit(1);
+            }
         }
-        Commands::Receive => {
-            println!("Receiver Mode Enabled!\nListening for offers...");
-            fsm::receiver_fsm::start_receiver_fsm().await;
+        Commands::Receive { stdout, dir, on_conflict } => {
+            eprintln!("Receiver Mode Enabled!\nListening for offers...");
+            let destination = if stdout {
+                tunnel::transfer::Destination::Stdout
+            } else {
+                let path = dir.unwrap_or_else(utils::dirs::default_receive_dir);
+                eprintln!("Saving to: {}", path.display());
+                let ask = platform::terminal::ask_conflict;
+                tunnel::transfer::Destination::Dir { path, on_conflict, ask }
+            };
+            let received = fsm::receiver_fsm::start_receiver_fsm(&backends, destination).await;
+            if !matches!(received, fsm::receiver_fsm::ReceiverState::ReceiveSuccess) {
+                std::process::exit(1);
+            }
     }
 }
 }
@@ -0,0 +1,119 @@
+//! The fling GATT profile, shared by the sender's peripheral and the
+//! receivers' centrals on every platform.
+//!
+//! The UUIDs come from one randomly generated base,
+//! `c488xxxx-6a58-4d91-ab7c-184eb93b5320`, with the service and each
+//! characteristic numbered in the `xxxx` field.
+//!
+//! | Characteristic | Access       | Value                                   |
+//! |----------------|--------------|-----------------------------------------|
+//! | version        | read         | `PROTOCOL_VERSION`, one byte            |
+//! | identity       | read         | the sender's display name, UTF-8        |
+//! | key exchange   | write/notify | X25519 public keys                      |
+//! | offer          | read         | the offer, sealed with the session key  |
+//! | confirm        | write        | the receiver's `Verdict`, one byte      |
+//!
+//! A waiting receiver advertises service data under `RECEIVER`, next to its
+//! display name, so a sender lists only devices that are ready for it:
+//!
+//! ```text
+//! version (1) | capabilities (1) | id (6)
+//! ```
+//!
+//! The id is the receiver's Bluetooth address, which the network
+//! credentials are derived from; the address the advertisement comes from
+//! may be a private one.
+
+use uuid::Uuid;
+
+pub const SERVICE: Uuid = Uuid::from_u128(0xc4880001_6a58_4d91_ab7c_184eb93b5320);
+pub const VERSION: Uuid = Uuid::from_u128(0xc4880002_6a58_4d91_ab7c_184eb93b5320);
+pub const IDENTITY: Uuid = Uuid::from_u128(0xc4880003_6a58_4d91_ab7c_184eb93b5320);
+pub const KEY_EXCHANGE: Uuid = Uuid::from_u128(0xc4880004_6a58_4d91_ab7c_184eb93b5320);
+pub const OFFER: Uuid = Uuid::from_u128(0xc4880005_6a58_4d91_ab7c_184eb93b5320);
+pub const CONFIRM: Uuid = Uuid::from_u128(0xc4880006_6a58_4d91_ab7c_184eb93b5320);
+/// The service data key a waiting receiver advertises under.
+pub const RECEIVER: Uuid = Uuid::from_u128(0xc4880007_6a58_4d91_ab7c_184eb93b5320);
+
+/// The receiver can join a network the sender hosts.
+pub const JOINS_WIFI: u8 = 0x01;
+/// The receiver tries the sender's LAN addresses first.
+pub const USES_LAN: u8 = 0x02;
+
+/// Bumped whenever the characteristics or their values change shape.
+pub const PROTOCOL_VERSION: u8 = 1;
+/// Longest display name served, in characters, so it fits one attribute.
+const MAX_NAME_LEN: usize = 64;
+
+/// The value of the version characteristic.
+pub fn version() -> Vec<u8> {
+    vec![PROTOCOL_VERSION]
+}
+
+/// Checks a peer's version characteristic against ours.
+pub fn check_version(value: &[u8]) -> Result<(), String> {
+    match value {
+        [PROTOCOL_VERSION] => Ok(()),
+        [other] => Err(format!(
+            "Peer speaks fling protocol version {}, this build speaks {}",
+            other, PROTOCOL_VERSION
+        )),
+        [] => Err("Peer does not report a fling protocol version".into()),
+        _ => Err("Peer sent a malformed protocol version".into()),
+    }
+}
+
+/// The value of the identity characteristic for `name`.
+pub fn identity(name: &str) -> Vec<u8> {
+    name.chars().take(MAX_NAME_LEN).collect::<String>().into_bytes()
+}
+
+/// Reads a peer's identity characteristic.
+pub fn parse_identity(value: &[u8]) -> String {
+    String::from_utf8_lossy(value).into_owned()
+}
+
+/// What a waiting receiver advertises besides its display name.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct ReceiverAdvert {
+    pub capabilities: u8,
+    /// The receiver's Bluetooth address, as `AA:BB:CC:DD:EE:FF`.
+    pub id: String,
+}
+
+impl ReceiverAdvert {
+    pub fn encode(&self) -> Result<Vec<u8>, String> {
+        let id = self
+            .id
+            .split(':')
+            .map(|byte| u8::from_str_radix(byte, 16))
+            .collect::<Result<Vec<u8>, _>>()
+            .ok()
+            .filter(|id| id.len() == 6)
+            .ok_or_else(|| format!("Not a Bluetooth address: {}", self.id))?;
+        Ok([vec![PROTOCOL_VERSION, self.capabilities], id].concat())
+    }
+
+    pub fn decode(data: &[u8]) -> Result<Self, String> {
+        let (version, rest) = data.split_first().ok_or("Empty receiver advertisement")?;
+        check_version(&[*version])?;
+        match rest {
+            [capabilities, id @ ..] if id.len() == 6 => Ok(Self {
+                capabilities: *capabilities,
+                id: id.iter().map(|b| format!("{:02X}", b)).collect::<Vec<_>>().join(":"),
+            }),
+            _ => Err("Malformed receiver advertisement".into()),
+        }
+    }
+
+    /// The capabilities for a log line, such as `wifi, lan`.
+    pub fn describe(&self) -> String {
+        let names = [(JOINS_WIFI, "wifi"), (USES_LAN, "lan")];
+        let names: Vec<_> = names
+            .into_iter()
+            .filter(|(bit, _)| self.capabilities & bit != 0)
+            .map(|(_, name)| name)
+            .collect();
+        if names.is_empty() { "none".into() } else { names.join(", ") }
+    }
+}
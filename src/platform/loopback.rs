@@ -0,0 +1,485 @@
+//! In-process backend: both peers in one process, no radios.
+//!
+//! `pair()` returns sender and receiver `Backends` wired to each other. The
+//! key exchange and the sealed offer travel over channels, the "Wi-Fi
+//! network" is a pair of credentials both sides have to derive identically,
+//! and the transfer itself runs over TCP on localhost. `Options` scripts the
+//! receiver's answers, shortens timeouts, can corrupt or cut the stream and
+//! can put peers without the key in the way, which is what the integration tests use
+//! it for.
+
+use crate::crypto::handshake::{Handshake, Role, SessionKeys, Verdict};
+use crate::crypto::stream::{open_message, seal_message};
+use crate::platform::{
+    Backends, DeviceInfo, Discovery, Interaction, KeyExchange, LinkProvider, Listening, Pairing,
+    Sighting, Transport, Via,
+};
+use crate::tunnel::transfer::{
+    Destination, Offer, Payload, TransferError, receive_session, send_session,
+};
+use crate::utils::net::{accept_verified, connect_verified};
+use std::fmt;
+use std::io;
+use std::pin::Pin;
+use std::net::{IpAddr, SocketAddr};
+use std::sync::Arc;
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::task::{Context, Poll};
+use std::time::Duration;
+use tokio::io::{AsyncReadExt, AsyncWrite, AsyncWriteExt};
+use tokio::net::{TcpListener, TcpStream};
+use tokio::sync::{Mutex, mpsc, oneshot};
+use tokio::time::{Instant, sleep, timeout};
+
+const RECEIVER_NAME: &str = "loopback-receiver";
+const RECEIVER_ADDRESS: &str = "02:00:00:00:00:01";
+const SENDER_NAME: &str = "loopback-sender";
+const SENDER_ADDRESS: &str = "02:00:00:00:00:02";
+
+/// How the loopback peers behave.
+#[derive(Clone, Debug)]
+pub struct Options {
+    /// How long any step waits for the other side.
+    pub timeout: Duration,
+    /// The receiver's answer to the code comparison.
+    pub codes_match: bool,
+    /// The receiver's answer to the offer.
+    pub accept: bool,
+    /// Flip the byte at this offset of the sender's encrypted stream.
+    pub corrupt_at: Option<u64>,
+    /// Drop the sender's first connection once this many bytes of its
+    /// encrypted stream have gone out, as a link that goes away would.
+    pub cut_at: Option<u64>,
+    /// What the sender's LAN addresses look like to the receiver.
+    pub lan: Lan,
+    /// Someone who does not hold the key connects to the sender first.
+    pub stranger: bool,
+}
+
+impl Default for Options {
+    fn default() -> Self {
+        Self {
+            timeout: Duration::from_secs(5),
+            codes_match: true,
+            accept: true,
+            corrupt_at: None,
+            cut_at: None,
+            lan: Lan::Absent,
+            stranger: false,
+        }
+    }
+}
+
+/// Whether the peers appear to share a network besides the hotspot.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum Lan {
+    /// The sender advertises no LAN address, so the hotspot is used.
+    Absent,
+    /// The advertised address reaches the sender.
+    Shared,
+    /// The advertised address refuses connections, as on another network.
+    Unreachable,
+    /// Something else answers at the advertised address, speaking the
+    /// protocol but without the key.
+    Impostor,
+}
+
+/// What the sender hands over when it starts the key exchange; the channels
+/// stand in for the GATT characteristics.
+struct Invitation {
+    sender_public: Vec<u8>,
+    receiver_public: oneshot::Sender<Vec<u8>>,
+    sealed_offer: oneshot::Receiver<Vec<u8>>,
+    verdict: oneshot::Sender<Verdict>,
+}
+
+struct Shared {
+    options: Options,
+    invitations_tx: mpsc::Sender<Invitation>,
+    invitations_rx: Mutex<mpsc::Receiver<Invitation>>,
+    /// The hosted network's SSID and password, while it is up.
+    network: std::sync::Mutex<Option<(String, String)>>,
+    hosted: AtomicBool,
+    listener: std::net::TcpListener,
+    /// The connection the sender's `accept` verified, with its key, for
+    /// `send`.
+    accepted: std::sync::Mutex<Option<(TcpStream, Vec<u8>)>>,
+    /// Whether a connection was already cut, so only the first one is.
+    cut: AtomicBool,
+}
+
+/// One side of a loopback pair. A single value serves as every backend.
+#[derive(Clone)]
+pub struct Loopback {
+    role: Role,
+    shared: Arc<Shared>,
+}
+
+pub type LoopbackBackends = Backends<Loopback, Loopback, Loopback, Loopback, Loopback>;
+
+/// Builds a connected sender and receiver.
+pub fn pair(options: Options) -> io::Result<(LoopbackBackends, LoopbackBackends)> {
+    let listener = std::net::TcpListener::bind(("127.0.0.1", 0))?;
+    listener.set_nonblocking(true)?;
+    let (invitations_tx, invitations_rx) = mpsc::channel(1);
+    let shared = Arc::new(Shared {
+        options,
+        invitations_tx,
+        invitations_rx: Mutex::new(invitations_rx),
+        network: std::sync::Mutex::new(None),
+        hosted: AtomicBool::new(false),
+        listener,
+        accepted: std::sync::Mutex::new(None),
+        cut: AtomicBool::new(false),
+    });
+
+    let backends = |role| {
+        let side = Loopback { role, shared: Arc::clone(&shared) };
+        Backends {
+            discovery: side.clone(),
+            key_exchange: side.clone(),
+            link: side.clone(),
+            transport: side.clone(),
+            ui: side,
+        }
+    };
+    Ok((backends(Role::Sender), backends(Role::Receiver)))
+}
+
+impl Loopback {
+    fn timeout(&self) -> Duration {
+        self.shared.options.timeout
+    }
+
+    /// Whether the sender ever brought up its network.
+    pub fn hosted(&self) -> bool {
+        self.shared.hosted.load(Ordering::Relaxed)
+    }
+
+    fn address(&self) -> io::Result<SocketAddr> {
+        self.shared.listener.local_addr()
+    }
+}
+
+impl Discovery for Loopback {
+    async fn scan(&self, found: mpsc::UnboundedSender<Sighting>) -> Result<(), String> {
+        let receiver = DeviceInfo {
+            name: RECEIVER_NAME.into(),
+            address: RECEIVER_ADDRESS.into(),
+            via: Via::Bluetooth(RECEIVER_ADDRESS.into()),
+            alias: None,
+            rssi: Some(-40),
+        };
+        found.send(Sighting::Seen(receiver)).map_err(|_| "Nobody is picking".to_string())
+    }
+
+    fn local_name(&self) -> String {
+        match self.role {
+            Role::Sender => SENDER_NAME.into(),
+            Role::Receiver => RECEIVER_NAME.into(),
+        }
+    }
+
+    fn local_address(&self) -> Option<String> {
+        match self.role {
+            Role::Sender => Some(SENDER_ADDRESS.into()),
+            Role::Receiver => Some(RECEIVER_ADDRESS.into()),
+        }
+    }
+}
+
+/// The receiver's half of a finished loopback key exchange.
+pub struct LoopbackPairing {
+    keys: SessionKeys,
+    offer: Offer,
+    verdict: oneshot::Sender<Verdict>,
+}
+
+impl fmt::Debug for LoopbackPairing {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "LoopbackPairing({})", self.keys.sas)
+    }
+}
+
+impl Pairing for LoopbackPairing {
+    fn sas(&self) -> &str {
+        &self.keys.sas
+    }
+
+    fn offer(&self) -> &Offer {
+        &self.offer
+    }
+
+    async fn respond(self, verdict: Verdict) -> Result<Vec<u8>, String> {
+        self.verdict.send(verdict).map_err(|_| "Sender went away".to_string())?;
+        match verdict {
+            Verdict::Accept => Ok(self.keys.key),
+            Verdict::Decline => Err("Transfer declined".into()),
+            Verdict::CodeMismatch => Err("Verification code rejected".into()),
+        }
+    }
+}
+
+impl KeyExchange for Loopback {
+    type Pairing = LoopbackPairing;
+
+    async fn offer(&self, _peer: &DeviceInfo, offer: &Offer) -> Result<Option<Vec<u8>>, String> {
+        let handshake = Handshake::new(Role::Sender);
+        let (public_tx, public_rx) = oneshot::channel();
+        let (offer_tx, offer_rx) = oneshot::channel();
+        let (verdict_tx, verdict_rx) = oneshot::channel();
+        let invitation = Invitation {
+            sender_public: handshake.public_key(),
+            receiver_public: public_tx,
+            sealed_offer: offer_rx,
+            verdict: verdict_tx,
+        };
+        self.shared
+            .invitations_tx
+            .send(invitation)
+            .await
+            .map_err(|_| "Receiver went away".to_string())?;
+
+        let receiver_public = timeout(self.timeout(), public_rx)
+            .await
+            .map_err(|_| "No receiver answered the key exchange".to_string())?
+            .map_err(|_| "Receiver went away".to_string())?;
+        let keys = handshake.finish(&receiver_public)?;
+        let sealed = seal_message(&keys.key, &offer.encode()).map_err(|e| e.to_string())?;
+        let _ = offer_tx.send(sealed);
+
+        match timeout(self.timeout(), verdict_rx).await {
+            Ok(Ok(Verdict::Accept)) => Ok(Some(keys.key)),
+            Ok(Ok(Verdict::Decline)) => Ok(None),
+            Ok(Ok(Verdict::CodeMismatch)) => Err("Receiver rejected the verification code".into()),
+            Ok(Err(_)) => Err("Receiver went away".into()),
+            Err(_) => Err("Pairing was not completed in time".into()),
+        }
+    }
+
+    async fn accept(&self) -> Result<LoopbackPairing, String> {
+        let invitation = timeout(self.timeout(), async {
+            self.shared.invitations_rx.lock().await.recv().await
+        })
+        .await
+        .map_err(|_| "No fling sender found".to_string())?
+        .ok_or_else(|| "Sender went away".to_string())?;
+
+        let handshake = Handshake::new(Role::Receiver);
+        invitation
+            .receiver_public
+            .send(handshake.public_key())
+            .map_err(|_| "Sender went away".to_string())?;
+        let keys = handshake.finish(&invitation.sender_public)?;
+
+        let sealed = timeout(self.timeout(), invitation.sealed_offer)
+            .await
+            .map_err(|_| "Sender did not send its offer".to_string())?
+            .map_err(|_| "Sender went away".to_string())?;
+        let offer = open_message(&keys.key, &sealed).map_err(|e| e.to_string())?;
+        let offer = Offer::decode(&offer)?;
+
+        Ok(LoopbackPairing { keys, offer, verdict: invitation.verdict })
+    }
+}
+
+impl LinkProvider for Loopback {
+    async fn host(&self, ssid: &str, password: &str) -> Result<IpAddr, String> {
+        *self.shared.network.lock().unwrap() = Some((ssid.to_string(), password.to_string()));
+        self.shared.hosted.store(true, Ordering::Relaxed);
+        Ok(self.address().map_err(|e| e.to_string())?.ip())
+    }
+
+    /// Succeeds once the sender hosts exactly these credentials, which is
+    /// what proves both sides derived the same ones.
+    async fn join(&self, ssid: &str, password: &str) -> Result<IpAddr, String> {
+        let deadline = Instant::now() + self.timeout();
+        loop {
+            let hosted = self.shared.network.lock().unwrap().clone();
+            if let Some((hosted_ssid, hosted_password)) = hosted
+                && hosted_ssid == ssid
+            {
+                if hosted_password != password {
+                    return Err(format!("Wrong password for '{}'", ssid));
+                }
+                return self.address().map(|addr| addr.ip()).map_err(|e| e.to_string());
+            }
+            if Instant::now() >= deadline {
+                return Err(format!("Network '{}' not found", ssid));
+            }
+            sleep(Duration::from_millis(20)).await;
+        }
+    }
+
+    /// Only the sender's side takes its network down; the receiver merely
+    /// leaves it.
+    async fn teardown(&self) {
+        if let Role::Sender = self.role {
+            self.shared.network.lock().unwrap().take();
+        }
+    }
+}
+
+impl Transport for Loopback {
+    async fn listen(&self) -> Result<Listening, String> {
+        let address = self.address().map_err(|e| e.to_string())?;
+        let lan = match self.shared.options.lan {
+            Lan::Absent => Vec::new(),
+            Lan::Shared => vec![address],
+            Lan::Unreachable => {
+                // A port that was free a moment ago refuses connections.
+                let closed = std::net::TcpListener::bind(("127.0.0.1", 0))
+                    .and_then(|listener| listener.local_addr())
+                    .map_err(|e| e.to_string())?;
+                vec![closed]
+            }
+            Lan::Impostor => {
+                let impostor =
+                    TcpListener::bind(("127.0.0.1", 0)).await.map_err(|e| e.to_string())?;
+                let address = impostor.local_addr().map_err(|e| e.to_string())?;
+                tokio::spawn(async move {
+                    // A well-formed challenge, then an answer made up of zeros.
+                    while let Ok((mut stream, _)) = impostor.accept().await {
+                        let mut reply = [0u8; 16 + 32];
+                        let _ = stream.write_all(b"FLPF").await;
+                        let _ = stream.write_all(&[0u8; 16]).await;
+                        let _ = stream.read_exact(&mut reply).await;
+                        let _ = stream.write_all(&[0u8; 32]).await;
+                    }
+                });
+                vec![address]
+            }
+        };
+        Ok(Listening { port: address.port(), lan })
+    }
+
+    /// The one listener already serves every "network".
+    async fn listen_on(&self, ip: IpAddr) -> Result<(), String> {
+        let address = self.address().map_err(|e| e.to_string())?;
+        if ip != address.ip() {
+            return Err(format!("{} is not this machine's address on the network", ip));
+        }
+        Ok(())
+    }
+
+    async fn accept(&self, key: &[u8], wait: Duration) -> Result<SocketAddr, TransferError> {
+        let listener = TcpListener::from_std(self.shared.listener.try_clone()?)?;
+        // Queued ahead of the receiver, so the sender has to turn it away.
+        let mut stranger = None;
+        if self.shared.options.stranger && self.shared.accepted.lock().unwrap().is_none() {
+            let mut stream = TcpStream::connect(self.address()?).await?;
+            stream.write_all(&[0u8; 16 + 32]).await?;
+            stranger = Some(stream);
+        }
+
+        let (stream, peer, connection_key) =
+            accept_verified(&[&listener], key, wait.min(self.timeout())).await?;
+        drop(stranger);
+        *self.shared.accepted.lock().unwrap() = Some((stream, connection_key));
+        Ok(peer)
+    }
+
+    async fn send(&self, payload: &mut Payload) -> Result<(), TransferError> {
+        let (socket, connection_key) = self
+            .shared
+            .accepted
+            .lock()
+            .unwrap()
+            .take()
+            .ok_or_else(|| TransferError::Fatal("No verified receiver to send to".into()))?;
+        let (read_half, write_half) = socket.into_split();
+        let cut_at = match self.shared.cut.swap(true, Ordering::Relaxed) {
+            false => self.shared.options.cut_at,
+            true => None,
+        };
+        let write_half = Corrupting {
+            inner: write_half,
+            at: self.shared.options.corrupt_at,
+            cut_at,
+            written: 0,
+        };
+        send_session(read_half, write_half, payload, &connection_key).await
+    }
+
+    async fn receive(
+        &self,
+        peers: &[SocketAddr],
+        wait: Duration,
+        destination: &Destination,
+        key: &[u8],
+    ) -> Result<(), TransferError> {
+        let (stream, connection_key) =
+            connect_verified(peers, wait.min(self.timeout()), key).await?;
+        let (read_half, write_half) = stream.into_split();
+        receive_session(read_half, write_half, destination, &connection_key).await
+    }
+}
+
+impl Interaction for Loopback {
+    async fn choose_device(
+        &self,
+        mut found: mpsc::UnboundedReceiver<Sighting>,
+    ) -> Option<DeviceInfo> {
+        while let Some(sighting) = found.recv().await {
+            if let Sighting::Seen(device) = sighting {
+                return Some(device);
+            }
+        }
+        None
+    }
+
+    async fn codes_match(&self, _sas: &str) -> bool {
+        self.shared.options.codes_match
+    }
+
+    async fn accept_offer(&self, _offer: &Offer) -> bool {
+        self.shared.options.accept
+    }
+}
+
+/// Passes writes through, flipping the byte at offset `at` on the way, and
+/// fails them once `cut_at` bytes have gone through.
+struct Corrupting<W> {
+    inner: W,
+    at: Option<u64>,
+    cut_at: Option<u64>,
+    written: u64,
+}
+
+impl<W: AsyncWrite + Unpin> AsyncWrite for Corrupting<W> {
+    fn poll_write(
+        mut self: Pin<&mut Self>,
+        cx: &mut Context<'_>,
+        buf: &[u8],
+    ) -> Poll<io::Result<usize>> {
+        let this = &mut *self;
+        let start = this.written;
+        let buf = match this.cut_at {
+            Some(cut_at) if start >= cut_at => {
+                return Poll::Ready(Err(io::Error::new(io::ErrorKind::BrokenPipe, "link cut")));
+            }
+            Some(cut_at) => &buf[..buf.len().min((cut_at - start) as usize)],
+            None => buf,
+        };
+        let poll = match this.at {
+            Some(at) if (start..start + buf.len() as u64).contains(&at) => {
+                let mut tampered = buf.to_vec();
+                tampered[(at - start) as usize] ^= 0xff;
+                Pin::new(&mut this.inner).poll_write(cx, &tampered)
+            }
+            _ => Pin::new(&mut this.inner).poll_write(cx, buf),
+        };
+        if let Poll::Ready(Ok(n)) = &poll {
+            this.written += *n as u64;
+        }
+        poll
+    }
+
+    fn poll_flush(mut self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<io::Result<()>> {
+        Pin::new(&mut self.inner).poll_flush(cx)
+    }
+
+    fn poll_shutdown(mut self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<io::Result<()>> {
+        Pin::new(&mut self.inner).poll_shutdown(cx)
+    }
+}
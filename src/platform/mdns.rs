@@ -0,0 +1,444 @@
+//! Discovery and key exchange over mDNS/DNS-SD, for machines without
+//! Bluetooth or peers that only share a wired network.
+//!
+//! The receiver listens on an ephemeral TCP port and advertises it as an
+//! `_fling._tcp` service whose TXT record carries its display name and the
+//! id the network credentials are derived from. The sender browses for the
+//! service and runs the same handshake as over GATT on that connection:
+//!
+//! ```text
+//! S → R  "FLKX" 0x01, sender public key (32)
+//! R → S  receiver public key (32)
+//! S → R  u16 length, sealed offer
+//! R → S  verdict (1)
+//! ```
+//!
+//! `WithMdns` puts this next to a radio backend, so one scan lists peers
+//! found either way and the receiver waits for a sender on both.
+
+use crate::crypto::handshake::{Handshake, Role, SessionKeys, Verdict};
+use crate::crypto::stream::{open_message, seal_message};
+use crate::platform::{DeviceInfo, Discovery, KeyExchange, Pairing, Sighting, Via};
+use crate::tunnel::transfer::Offer;
+use futures::future::{self, FutureExt, LocalBoxFuture};
+use mdns_sd::{ServiceDaemon, ServiceEvent, ServiceInfo};
+use rand::RngCore;
+use std::collections::HashMap;
+use std::fmt;
+use std::net::{IpAddr, SocketAddr};
+use std::time::Duration;
+use tokio::io::{AsyncReadExt, AsyncWriteExt};
+use tokio::net::{TcpListener, TcpStream};
+use tokio::sync::mpsc;
+use tokio::task::JoinSet;
+use tokio::time::{Instant, timeout, timeout_at};
+
+const SERVICE_TYPE: &str = "_fling._tcp.local.";
+const MAGIC: &[u8; 5] = b"FLKX\x01";
+/// How long the receiver stays advertised waiting for a sender.
+const LISTEN_TIMEOUT: Duration = Duration::from_secs(60);
+/// How long one side waits for the other's next handshake message.
+const STEP_TIMEOUT: Duration = Duration::from_secs(10);
+/// How long the sender waits for the receiver to confirm the code.
+const PAIRING_TIMEOUT: Duration = Duration::from_secs(60);
+
+/// The mDNS responder and the id this machine advertises when it has no
+/// Bluetooth address to offer.
+#[derive(Clone)]
+pub struct Mdns {
+    daemon: ServiceDaemon,
+    fallback_id: String,
+}
+
+impl Mdns {
+    pub fn new() -> Result<Self, String> {
+        let daemon = ServiceDaemon::new().map_err(|e| format!("Failed to start mDNS: {}", e))?;
+        // A locally administered MAC-style id, so the SSID derivation
+        // treats it like a Bluetooth address.
+        let mut bytes = [0u8; 5];
+        rand::thread_rng().fill_bytes(&mut bytes);
+        let fallback_id = std::iter::once(0x02)
+            .chain(bytes)
+            .map(|b| format!("{:02X}", b))
+            .collect::<Vec<_>>()
+            .join(":");
+        Ok(Self { daemon, fallback_id })
+    }
+
+    /// Reports the receivers advertising on the local network to `found`
+    /// until dropped, and again when they withdraw their service.
+    pub async fn browse(&self, found: mpsc::UnboundedSender<Sighting>) -> Result<(), String> {
+        eprintln!("[mDNS] Browsing for receivers on the local network...");
+        let events = self
+            .daemon
+            .browse(SERVICE_TYPE)
+            .map_err(|e| format!("mDNS browse failed: {}", e))?;
+        let _browsing = Browsing { daemon: self.daemon.clone() };
+
+        // Where each instance was found, since its removal names only it.
+        let mut seen = HashMap::new();
+        while let Ok(event) = events.recv_async().await {
+            let sighting = match event {
+                ServiceEvent::ServiceResolved(info) => match device_from(&info) {
+                    Some(device) => {
+                        seen.insert(info.get_fullname().to_string(), device.via.clone());
+                        Sighting::Seen(device)
+                    }
+                    None => continue,
+                },
+                ServiceEvent::ServiceRemoved(_, fullname) => match seen.remove(&fullname) {
+                    Some(via) => Sighting::Lost(via),
+                    None => continue,
+                },
+                _ => continue,
+            };
+            if found.send(sighting).is_err() {
+                break;
+            }
+        }
+        Ok(())
+    }
+
+    /// Sender: runs the handshake with the receiver at `peer` and lets it
+    /// read `offer`. Returns the session key once accepted, or `None` if the
+    /// receiver declined.
+    pub async fn offer(&self, peer: SocketAddr, offer: &Offer) -> Result<Option<Vec<u8>>, String> {
+        let mut stream = timeout(STEP_TIMEOUT, TcpStream::connect(peer))
+            .await
+            .map_err(|_| format!("{} did not answer", peer))?
+            .map_err(|e| format!("Cannot reach {}: {}", peer, e))?;
+        eprintln!("[mDNS] Connected to {}. Exchanging keys...", peer);
+
+        let handshake = Handshake::new(Role::Sender);
+        let mut hello = MAGIC.to_vec();
+        hello.extend_from_slice(&handshake.public_key());
+        stream.write_all(&hello).await.map_err(|e| e.to_string())?;
+
+        let mut receiver_public = [0u8; 32];
+        step(stream.read_exact(&mut receiver_public)).await?;
+        let keys = handshake.finish(&receiver_public)?;
+
+        let sealed = seal_message(&keys.key, &offer.encode()).map_err(|e| e.to_string())?;
+        let len = u16::try_from(sealed.len()).map_err(|_| "Offer is too large to send".to_string())?;
+        stream.write_u16(len).await.map_err(|e| e.to_string())?;
+        stream.write_all(&sealed).await.map_err(|e| e.to_string())?;
+
+        eprintln!(
+            "[Pairing] Verification code: {} (check that the receiver shows the same)",
+            keys.sas
+        );
+        let verdict = timeout(PAIRING_TIMEOUT, stream.read_u8())
+            .await
+            .map_err(|_| "Receiver did not confirm in time".to_string())?
+            .map_err(|e| format!("Receiver went away: {}", e))?;
+        match Verdict::from_byte(&[verdict]) {
+            Some(Verdict::Accept) => Ok(Some(keys.key)),
+            Some(Verdict::Decline) => Ok(None),
+            Some(Verdict::CodeMismatch) => Err("Receiver rejected the verification code".into()),
+            None => Err(format!("Unexpected verdict {}", verdict)),
+        }
+    }
+
+    /// Receiver: advertises this machine as `name`/`id` and waits for a
+    /// sender to complete the handshake. Connections that do not speak it
+    /// are dropped and the wait goes on.
+    pub async fn accept(&self, name: &str, id: &str) -> Result<MdnsPairing, String> {
+        let listener = TcpListener::bind(("0.0.0.0", 0))
+            .await
+            .map_err(|e| format!("Cannot listen for senders: {}", e))?;
+        let port = listener.local_addr().map_err(|e| e.to_string())?.port();
+        let _advertised = self.advertise(name, id, port)?;
+        eprintln!("[mDNS] Advertising '{}' on port {}. Waiting for a sender...", name, port);
+
+        // Each hello runs in its own task, so a host that connects and goes
+        // quiet cannot hold up senders behind it.
+        let deadline = Instant::now() + LISTEN_TIMEOUT;
+        let mut hellos = JoinSet::new();
+        loop {
+            tokio::select! {
+                accepted = timeout_at(deadline, listener.accept()) => {
+                    let (stream, peer) = accepted
+                        .map_err(|_| "No fling sender found".to_string())?
+                        .map_err(|e| e.to_string())?;
+                    hellos.spawn(async move { (peer, respond_to_hello(stream).await) });
+                }
+                Some(joined) = hellos.join_next() => {
+                    let Ok((peer, result)) = joined else { continue };
+                    match result {
+                        Ok((stream, keys, offer)) => {
+                            eprintln!("[mDNS] Connected to sender at {}. Key exchange complete.", peer);
+                            return Ok(MdnsPairing { stream, peer, keys, offer });
+                        }
+                        Err(e) => eprintln!("[mDNS] Ignoring {}: {}", peer, e),
+                    }
+                }
+            }
+        }
+    }
+
+    fn advertise(&self, name: &str, id: &str, port: u16) -> Result<Advertisement, String> {
+        let host: String = whoami::fallible::hostname()
+            .unwrap_or_else(|_| "fling".into())
+            .chars()
+            .map(|c| if c.is_ascii_alphanumeric() { c } else { '-' })
+            .collect();
+        let suffix: String = id.chars().filter(char::is_ascii_hexdigit).collect();
+        let instance = format!("{} {}", name, &suffix[suffix.len().saturating_sub(4)..]);
+        let properties = [("name", name), ("id", id)];
+
+        let info = ServiceInfo::new(
+            SERVICE_TYPE,
+            &instance,
+            &format!("{}.local.", host),
+            "",
+            port,
+            &properties[..],
+        )
+        .map_err(|e| format!("Invalid mDNS service: {}", e))?
+        .enable_addr_auto();
+        let fullname = info.get_fullname().to_string();
+        self.daemon
+            .register(info)
+            .map_err(|e| format!("Failed to advertise over mDNS: {}", e))?;
+        Ok(Advertisement { daemon: self.daemon.clone(), fullname })
+    }
+}
+
+/// Stops browsing once the sender stops scanning, however that ends.
+struct Browsing {
+    daemon: ServiceDaemon,
+}
+
+impl Drop for Browsing {
+    fn drop(&mut self) {
+        let _ = self.daemon.stop_browse(SERVICE_TYPE);
+    }
+}
+
+/// Withdraws the service once the receiver stops waiting, however that ends.
+struct Advertisement {
+    daemon: ServiceDaemon,
+    fullname: String,
+}
+
+impl Drop for Advertisement {
+    fn drop(&mut self) {
+        let _ = self.daemon.unregister(&self.fullname);
+    }
+}
+
+fn device_from(info: &ServiceInfo) -> Option<DeviceInfo> {
+    let id = info.get_property_val_str("id")?.to_string();
+    // The network credentials are derived from the id, so it has to look
+    // like the ones `Mdns::new` and the Bluetooth advert produce.
+    if !is_device_id(&id) {
+        eprintln!("[mDNS] Ignoring {}: malformed id {:?}", info.get_fullname(), id);
+        return None;
+    }
+    let instance = info.get_fullname().trim_end_matches(SERVICE_TYPE);
+    let instance = instance.trim_end_matches('.').to_string();
+    let (name, alias) = match info.get_property_val_str("name") {
+        Some(name) => (name.to_string(), Some(instance)),
+        None => (instance, None),
+    };
+    // IPv4 first; an IPv6 link-local address is useless without its scope.
+    let ip = info
+        .get_addresses()
+        .iter()
+        .copied()
+        .filter(|ip| !matches!(ip, IpAddr::V6(v6) if v6.is_unicast_link_local()))
+        .min_by_key(IpAddr::is_ipv6)?;
+    let via = Via::Mdns(SocketAddr::new(ip, info.get_port()));
+    Some(DeviceInfo { name, address: id, via, alias, rssi: None })
+}
+
+/// Whether `id` is a MAC-style `AA:BB:CC:DD:EE:FF`, in uppercase hex.
+fn is_device_id(id: &str) -> bool {
+    let bytes: Vec<&str> = id.split(':').collect();
+    bytes.len() == 6
+        && bytes.iter().all(|byte| {
+            byte.len() == 2 && byte.chars().all(|c| c.is_ascii_digit() || ('A'..='F').contains(&c))
+        })
+}
+
+async fn step<T>(read: impl Future<Output = std::io::Result<T>>) -> Result<T, String> {
+    timeout(STEP_TIMEOUT, read)
+        .await
+        .map_err(|_| "peer stopped answering".to_string())?
+        .map_err(|e| e.to_string())
+}
+
+async fn respond_to_hello(
+    mut stream: TcpStream,
+) -> Result<(TcpStream, SessionKeys, Offer), String> {
+    let mut hello = [0u8; MAGIC.len() + 32];
+    step(stream.read_exact(&mut hello)).await?;
+    let (magic, sender_public) = hello.split_at(MAGIC.len());
+    if magic != MAGIC {
+        return Err("not a fling sender".into());
+    }
+
+    let handshake = Handshake::new(Role::Receiver);
+    stream.write_all(&handshake.public_key()).await.map_err(|e| e.to_string())?;
+    let keys = handshake.finish(sender_public)?;
+
+    let len = step(stream.read_u16()).await?;
+    let mut sealed = vec![0u8; len as usize];
+    step(stream.read_exact(&mut sealed)).await?;
+    let offer = open_message(&keys.key, &sealed).map_err(|e| e.to_string())?;
+    let offer = Offer::decode(&offer)?;
+
+    Ok((stream, keys, offer))
+}
+
+/// The receiver's half of a finished mDNS key exchange.
+pub struct MdnsPairing {
+    stream: TcpStream,
+    peer: SocketAddr,
+    keys: SessionKeys,
+    offer: Offer,
+}
+
+impl fmt::Debug for MdnsPairing {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "MdnsPairing({})", self.peer)
+    }
+}
+
+impl Pairing for MdnsPairing {
+    fn sas(&self) -> &str {
+        &self.keys.sas
+    }
+
+    fn offer(&self) -> &Offer {
+        &self.offer
+    }
+
+    async fn respond(mut self, verdict: Verdict) -> Result<Vec<u8>, String> {
+        let result = self.stream.write_u8(verdict.to_byte()).await;
+        let _ = self.stream.shutdown().await;
+        result.map_err(|e| format!("Failed to send verdict: {}", e))?;
+
+        match verdict {
+            Verdict::Accept => Ok(self.keys.key),
+            Verdict::Decline => Err("Transfer declined".into()),
+            Verdict::CodeMismatch => Err("Verification code rejected".into()),
+        }
+    }
+}
+
+/// A radio backend with mDNS beside it. Either may be missing, e.g. on a
+/// desktop without Bluetooth, but not both.
+#[derive(Clone)]
+pub struct WithMdns<B> {
+    pub radio: Option<B>,
+    pub mdns: Option<Mdns>,
+}
+
+impl<B: Discovery> Discovery for WithMdns<B> {
+    async fn scan(&self, found: mpsc::UnboundedSender<Sighting>) -> Result<(), String> {
+        let radio_found = found.clone();
+        let radio = async move {
+            match &self.radio {
+                Some(radio) => radio.scan(radio_found).await,
+                None => Ok(()),
+            }
+        };
+        let mdns = async move {
+            match &self.mdns {
+                Some(mdns) => mdns.browse(found).await,
+                None => Ok(()),
+            }
+        };
+
+        match tokio::join!(radio, mdns) {
+            (Err(radio), Err(mdns)) => Err(format!("{}; {}", radio, mdns)),
+            (radio, mdns) => {
+                for e in [radio, mdns].into_iter().filter_map(Result::err) {
+                    eprintln!("[Scanning] {}", e);
+                }
+                Ok(())
+            }
+        }
+    }
+
+    fn local_name(&self) -> String {
+        match &self.radio {
+            Some(radio) => radio.local_name(),
+            None => whoami::fallible::hostname().unwrap_or_else(|_| "unknown".to_string()),
+        }
+    }
+
+    fn local_address(&self) -> Option<String> {
+        self.radio
+            .as_ref()
+            .and_then(|radio| radio.local_address())
+            .or_else(|| self.mdns.as_ref().map(|mdns| mdns.fallback_id.clone()))
+    }
+}
+
+/// A pairing made over whichever backend the sender turned up on.
+#[derive(Debug)]
+pub enum AnyPairing<P> {
+    Radio(P),
+    Mdns(Box<MdnsPairing>),
+}
+
+impl<P: Pairing> Pairing for AnyPairing<P> {
+    fn sas(&self) -> &str {
+        match self {
+            AnyPairing::Radio(pairing) => pairing.sas(),
+            AnyPairing::Mdns(pairing) => pairing.sas(),
+        }
+    }
+
+    fn offer(&self) -> &Offer {
+        match self {
+            AnyPairing::Radio(pairing) => pairing.offer(),
+            AnyPairing::Mdns(pairing) => pairing.offer(),
+        }
+    }
+
+    async fn respond(self, verdict: Verdict) -> Result<Vec<u8>, String> {
+        match self {
+            AnyPairing::Radio(pairing) => pairing.respond(verdict).await,
+            AnyPairing::Mdns(pairing) => (*pairing).respond(verdict).await,
+        }
+    }
+}
+
+impl<B: Discovery + KeyExchange> KeyExchange for WithMdns<B> {
+    type Pairing = AnyPairing<B::Pairing>;
+
+    async fn offer(&self, peer: &DeviceInfo, offer: &Offer) -> Result<Option<Vec<u8>>, String> {
+        match (&peer.via, &self.radio, &self.mdns) {
+            (Via::Bluetooth(_), Some(radio), _) => radio.offer(peer, offer).await,
+            (Via::Mdns(addr), _, Some(mdns)) => mdns.offer(*addr, offer).await,
+            _ => Err(format!("No backend can reach {}", peer)),
+        }
+    }
+
+    /// Waits on every available backend; the first sender to complete a
+    /// handshake wins, and a backend giving up only matters if all do.
+    async fn accept(&self) -> Result<Self::Pairing, String> {
+        let mut waits: Vec<LocalBoxFuture<'_, Result<Self::Pairing, String>>> = Vec::new();
+        if let Some(radio) = &self.radio {
+            waits.push(async move { radio.accept().await.map(AnyPairing::Radio) }.boxed_local());
+        }
+        if let Some(mdns) = &self.mdns {
+            let name = self.local_name();
+            let id = self.local_address().unwrap_or_default();
+            waits.push(
+                async move {
+                    let pairing = mdns.accept(&name, &id).await?;
+                    Ok(AnyPairing::Mdns(Box::new(pairing)))
+                }
+                .boxed_local(),
+            );
+        }
+        if waits.is_empty() {
+            return Err("Neither Bluetooth nor mDNS is available".into());
+        }
+        future::select_ok(waits).await.map(|(pairing, _)| pairing)
+    }
+}
@@ -0,0 +1,234 @@
+//! Seams between the state machines and the platform code.
+//!
+//! The FSMs only talk to these traits; `native()` wires up the Linux or
+//! macOS implementations, and anything else (the in-process `loopback`
+//! backend, a mock in a test) can be swapped in by building `Backends` by
+//! hand.
+
+pub mod gatt;
+pub mod loopback;
+pub mod mdns;
+pub mod terminal;
+
+use crate::crypto::handshake::Verdict;
+use crate::tunnel::transfer::{Destination, Offer, Payload, TransferError};
+use std::fmt;
+use std::net::{IpAddr, SocketAddr};
+use std::time::Duration;
+use tokio::sync::mpsc;
+
+/// A nearby device the sender can offer files to.
+#[derive(Clone, Debug)]
+pub struct DeviceInfo {
+    pub name: String,
+    /// The receiver's Bluetooth address, or the id it advertises in its
+    /// place; part of the network credentials.
+    pub address: String,
+    pub via: Via,
+    /// Another name the device goes by, such as one set with
+    /// `bluetoothctl set-alias` or its mDNS instance name.
+    pub alias: Option<String>,
+    /// Signal strength in dBm, where the radio reports one.
+    pub rssi: Option<i16>,
+}
+
+impl DeviceInfo {
+    /// Whether `other` is a later sighting of the same device.
+    pub fn same_device(&self, other: &DeviceInfo) -> bool {
+        self.address == other.address && self.via == other.via
+    }
+}
+
+/// What a scan reports about a receiver.
+#[derive(Clone, Debug)]
+pub enum Sighting {
+    /// The receiver turned up, or what is known about it changed.
+    Seen(DeviceInfo),
+    /// The receiver found this way is gone.
+    Lost(Via),
+}
+
+/// Which receiver the sender goes for.
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
+pub enum Target {
+    /// Whichever one the user picks through `Interaction::choose_device`.
+    #[default]
+    Ask,
+    /// The first one found.
+    First,
+    /// The first one whose name, alias or Bluetooth address this is.
+    Named(String),
+}
+
+impl Target {
+    pub fn matches(&self, device: &DeviceInfo) -> bool {
+        match self {
+            Target::Ask | Target::First => true,
+            Target::Named(wanted) => {
+                let seen = match &device.via {
+                    Via::Bluetooth(seen) => Some(seen),
+                    Via::Mdns(_) => None,
+                };
+                std::iter::once(&device.name)
+                    .chain(&device.alias)
+                    .chain(std::iter::once(&device.address))
+                    .chain(seen)
+                    .any(|known| known.eq_ignore_ascii_case(wanted))
+            }
+        }
+    }
+}
+
+impl fmt::Display for Target {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Target::Ask => write!(f, "the receiver the user picks"),
+            Target::First => write!(f, "the first receiver found"),
+            Target::Named(name) => write!(f, "receiver '{}'", name),
+        }
+    }
+}
+
+/// Where a device was found, and so how the key exchange reaches it.
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub enum Via {
+    /// Advertising from this Bluetooth address, which differs from
+    /// `address` when the receiver uses LE privacy.
+    Bluetooth(String),
+    /// An `_fling._tcp` service listening at this address.
+    Mdns(SocketAddr),
+}
+
+impl fmt::Display for DeviceInfo {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match &self.via {
+            Via::Bluetooth(_) => write!(f, "{} ({})", self.name, self.address),
+            Via::Mdns(addr) => write!(f, "{} ({}, LAN)", self.name, addr),
+        }
+    }
+}
+
+/// Finding peers and describing this machine to them.
+#[allow(async_fn_in_trait)]
+pub trait Discovery {
+    /// Sender: reports receivers to `found` as they turn up, again when what
+    /// is known about them changes, and once more when they go away. Runs
+    /// until dropped, or until no more can turn up.
+    async fn scan(&self, found: mpsc::UnboundedSender<Sighting>) -> Result<(), String>;
+    /// The name this machine is known by; part of the network credentials.
+    fn local_name(&self) -> String;
+    /// This machine's radio address, if it has one.
+    fn local_address(&self) -> Option<String>;
+}
+
+/// A key exchange that has finished on the wire but still waits for the
+/// user to compare the verification code and look at the offer.
+#[allow(async_fn_in_trait)]
+pub trait Pairing: fmt::Debug {
+    fn sas(&self) -> &str;
+    fn offer(&self) -> &Offer;
+    /// Sends the verdict to the sender and returns the session key if the
+    /// transfer was accepted.
+    async fn respond(self, verdict: Verdict) -> Result<Vec<u8>, String>;
+}
+
+/// The authenticated key exchange that precedes every transfer.
+#[allow(async_fn_in_trait)]
+pub trait KeyExchange {
+    type Pairing: Pairing;
+
+    /// Sender: lets `peer` run the handshake and read `offer`. Returns the
+    /// session key once accepted, or `None` if the receiver declined.
+    async fn offer(&self, peer: &DeviceInfo, offer: &Offer) -> Result<Option<Vec<u8>>, String>;
+    /// Receiver: finds a sender and runs the handshake with it.
+    async fn accept(&self) -> Result<Self::Pairing, String>;
+}
+
+/// The network link the transfer runs over.
+#[allow(async_fn_in_trait)]
+pub trait LinkProvider {
+    /// Sender: brings up a network for the receiver to join and returns
+    /// this machine's address on it.
+    async fn host(&self, ssid: &str, password: &str) -> Result<IpAddr, String>;
+    /// Receiver: joins the sender's network and returns the sender's
+    /// address on it.
+    async fn join(&self, ssid: &str, password: &str) -> Result<IpAddr, String>;
+    /// Either side: undoes what `host` or `join` changed, restoring the
+    /// network state from before the transfer.
+    async fn teardown(&self);
+}
+
+/// Where the sender listens, as advertised in the offer.
+#[derive(Debug, Clone, Default)]
+pub struct Listening {
+    /// The port on every network, the hotspot included once it is up.
+    pub port: u16,
+    /// Addresses a receiver on one of this machine's networks could use.
+    pub lan: Vec<SocketAddr>,
+}
+
+/// Moves the payload once the link is up.
+#[allow(async_fn_in_trait)]
+pub trait Transport {
+    /// Sender: starts listening on the networks this machine is already on,
+    /// before the offer goes out.
+    async fn listen(&self) -> Result<Listening, String>;
+    /// Sender: also listens on `ip`, this machine's address on the network
+    /// `LinkProvider::host` brought up, on the port `listen` advertised.
+    async fn listen_on(&self, ip: IpAddr) -> Result<(), String>;
+    /// Sender: waits up to `wait` for a receiver that proves it holds `key`,
+    /// turning away any other connection, and keeps the verified one for
+    /// `send`. Nobody proving it in time counts as
+    /// `TransferError::Interrupted`.
+    async fn accept(&self, key: &[u8], wait: Duration) -> Result<SocketAddr, TransferError>;
+    /// Sender: runs the session over the connection `accept` verified, under
+    /// the key derived for that connection.
+    async fn send(&self, payload: &mut Payload) -> Result<(), TransferError>;
+    /// Receiver: connects to the first of `peers` that answers and proves it
+    /// holds `key` within `wait`.
+    async fn receive(
+        &self,
+        peers: &[SocketAddr],
+        wait: Duration,
+        destination: &Destination,
+        key: &[u8],
+    ) -> Result<(), TransferError>;
+}
+
+/// The decisions a user makes along the way.
+#[allow(async_fn_in_trait)]
+pub trait Interaction {
+    /// Sender: picks one of the devices coming and going on `found` while
+    /// the scan goes on, or `None` to give up. `found` closes when the scan
+    /// ends.
+    async fn choose_device(&self, found: mpsc::UnboundedReceiver<Sighting>) -> Option<DeviceInfo>;
+    /// Receiver: whether the sender shows the same verification code.
+    async fn codes_match(&self, sas: &str) -> bool;
+    /// Receiver: whether to take the offered transfer.
+    async fn accept_offer(&self, offer: &Offer) -> bool;
+}
+
+/// One implementation of every seam, as handed to the FSMs.
+pub struct Backends<D, K, L, T, U> {
+    pub discovery: D,
+    pub key_exchange: K,
+    pub link: L,
+    pub transport: T,
+    pub ui: U,
+}
+
+/// Overrides for the Wi-Fi link `native()` would otherwise work out itself.
+#[derive(Debug, Clone, Default)]
+pub struct LinkOptions {
+    /// The wireless interface to use instead of the detected one.
+    pub iface: Option<String>,
+    /// Sender: the channel to host on instead of the one chosen for the
+    /// hardware and regulatory domain.
+    pub channel: Option<u32>,
+}
+
+#[cfg(target_os = "linux")]
+pub use crate::linux::backend::native;
+
+#[cfg(target_os = "macos")]
+pub use crate::macos::backend::native;
@@ -0,0 +1,206 @@
+use crate::platform::{DeviceInfo, Interaction, Sighting, Via};
+use crate::tunnel::archive::ConflictPolicy;
+use crate::tunnel::transfer::Offer;
+use console::{Key, Term, style, truncate_str};
+use dialoguer::{Confirm, Select, theme::ColorfulTheme};
+use std::cmp::Reverse;
+use std::io::{self, IsTerminal};
+use std::ops::ControlFlow;
+use std::sync::mpsc as blocking;
+use tokio::sync::mpsc;
+
+/// Asks on the terminal with `dialoguer`, and lists devices with `Picker`.
+pub struct Terminal;
+
+impl Interaction for Terminal {
+    async fn choose_device(
+        &self,
+        found: mpsc::UnboundedReceiver<Sighting>,
+    ) -> Option<DeviceInfo> {
+        Picker::default().run(&Term::stderr(), found).await
+    }
+
+    async fn codes_match(&self, sas: &str) -> bool {
+        eprintln!("[Verifying] Verification code: {}", sas);
+        confirm("Does the sender show the same code?", false).await
+    }
+
+    async fn accept_offer(&self, offer: &Offer) -> bool {
+        eprintln!("[Verifying] Incoming offer\n{}", offer);
+        confirm("Accept this transfer?", true).await
+    }
+}
+
+/// Asks a yes/no question on a blocking thread, so the runtime keeps
+/// serving the connection while the user makes up their mind.
+async fn confirm(prompt: &'static str, default: bool) -> bool {
+    tokio::task::spawn_blocking(move || {
+        Confirm::with_theme(&ColorfulTheme::default())
+            .with_prompt(prompt)
+            .default(default)
+            .interact()
+            .unwrap_or(false)
+    })
+    .await
+    .unwrap_or(false)
+}
+
+/// Whether `Picker` can run here: it draws on stderr and reads keys the
+/// way `console` does, from stdin if that is a terminal, else `/dev/tty`.
+pub fn can_pick() -> bool {
+    let keyboard = io::stdin().is_terminal() || std::fs::File::open("/dev/tty").is_ok();
+    Term::stderr().is_term() && keyboard
+}
+
+/// Asks what to do about `rel`, which already exists, for
+/// `--on-conflict ask`. Blocks until answered.
+pub fn ask_conflict(rel: &str) -> Result<ConflictPolicy, String> {
+    let choice = Select::with_theme(&ColorfulTheme::default())
+        .with_prompt(format!("'{}' already exists", rel))
+        .items(&["Keep both", "Overwrite", "Skip"])
+        .default(0)
+        .interact()
+        .map_err(|e| format!("Cannot ask about '{}': {}", rel, e))?;
+    Ok([ConflictPolicy::Rename, ConflictPolicy::Overwrite, ConflictPolicy::Skip][choice])
+}
+
+/// The receivers found so far, redrawn as the scan reports them: strongest
+/// signal first, those found without a signal reading (on the LAN) last.
+#[derive(Default)]
+struct Picker {
+    devices: Vec<DeviceInfo>,
+    cursor: usize,
+    scanning: bool,
+    /// How many lines the last frame took, to clear before the next.
+    drawn: usize,
+}
+
+impl Picker {
+    async fn run(
+        mut self,
+        term: &Term,
+        mut found: mpsc::UnboundedReceiver<Sighting>,
+    ) -> Option<DeviceInfo> {
+        let (mut keys, next_key) = read_keys();
+        self.scanning = true;
+        loop {
+            tokio::select! {
+                sighting = found.recv(), if self.scanning => match sighting {
+                    Some(Sighting::Seen(device)) => self.update(device),
+                    Some(Sighting::Lost(via)) => self.remove(&via),
+                    None => self.scanning = false,
+                },
+                key = keys.recv() => match self.press(key) {
+                    ControlFlow::Break(chosen) => {
+                        let _ = term.clear_last_lines(self.drawn);
+                        return chosen;
+                    }
+                    ControlFlow::Continue(()) => {
+                        let _ = next_key.send(());
+                    }
+                },
+            }
+            // Until something turns up, the scan's own log lines say more.
+            if !self.devices.is_empty() || !self.scanning {
+                let _ = self.draw(term);
+            }
+        }
+    }
+
+    /// Adds `device`, or updates it if it was seen before, keeping the
+    /// cursor on the device it was on.
+    fn update(&mut self, device: DeviceInfo) {
+        let selected = self.devices.get(self.cursor).cloned();
+        match self.devices.iter_mut().find(|known| known.same_device(&device)) {
+            Some(known) => *known = device,
+            None => self.devices.push(device),
+        }
+        self.devices.sort_by_key(|device| Reverse(device.rssi.map_or(i32::MIN, i32::from)));
+        self.follow(selected);
+    }
+
+    /// Drops the device found `via` this way. If the cursor was on it, the
+    /// cursor stays on the same line.
+    fn remove(&mut self, via: &Via) {
+        let selected = self.devices.get(self.cursor).cloned();
+        self.devices.retain(|device| device.via != *via);
+        self.follow(selected);
+    }
+
+    /// Moves the cursor back onto `selected`, wherever it is now, or keeps
+    /// it within the list if `selected` is gone.
+    fn follow(&mut self, selected: Option<DeviceInfo>) {
+        let position = selected
+            .and_then(|selected| self.devices.iter().position(|d| d.same_device(&selected)));
+        self.cursor = position.unwrap_or(self.cursor.min(self.devices.len().saturating_sub(1)));
+    }
+
+    fn press(&mut self, key: Option<io::Result<Key>>) -> ControlFlow<Option<DeviceInfo>> {
+        match key {
+            Some(Ok(Key::ArrowUp | Key::Char('k'))) => {
+                self.cursor = self.cursor.saturating_sub(1);
+            }
+            Some(Ok(Key::ArrowDown | Key::Char('j'))) => {
+                self.cursor = (self.cursor + 1).min(self.devices.len().saturating_sub(1));
+            }
+            Some(Ok(Key::Enter)) if !self.devices.is_empty() => {
+                return ControlFlow::Break(Some(self.devices[self.cursor].clone()));
+            }
+            Some(Ok(Key::Escape | Key::Char('q'))) | Some(Err(_)) | None => {
+                return ControlFlow::Break(None);
+            }
+            Some(Ok(_)) => {}
+        }
+        ControlFlow::Continue(())
+    }
+
+    fn draw(&mut self, term: &Term) -> io::Result<()> {
+        term.clear_last_lines(self.drawn)?;
+        let width = term.size().1 as usize;
+        let status = if self.scanning { "scanning" } else { "scan ended" };
+        let mut lines = vec![format!(
+            "Select a receiver ({}; ↑/↓ to move, Enter to pick, Esc to cancel)",
+            status
+        )];
+        if self.devices.is_empty() {
+            lines.push("  No receivers found.".into());
+        }
+        for (i, device) in self.devices.iter().enumerate() {
+            let signal = match device.rssi {
+                Some(rssi) => format!("{:>4} dBm", rssi),
+                None => "     LAN".into(),
+            };
+            let marker = if i == self.cursor { '>' } else { ' ' };
+            let line = format!("{} {}  {}", marker, signal, device);
+            let line = truncate_str(&line, width, "…");
+            lines.push(if i == self.cursor {
+                style(line).cyan().bold().to_string()
+            } else {
+                line.into_owned()
+            });
+        }
+        for line in &lines {
+            term.write_line(line)?;
+        }
+        self.drawn = lines.len();
+        Ok(())
+    }
+}
+
+/// Reads keys on a thread of its own, one per request on the returned
+/// sender, so that no read is left waiting, with the terminal in raw mode,
+/// once the picker is done.
+fn read_keys() -> (mpsc::UnboundedReceiver<io::Result<Key>>, blocking::Sender<()>) {
+    let (keys_tx, keys) = mpsc::unbounded_channel();
+    let (next_key, wanted) = blocking::channel();
+    std::thread::spawn(move || {
+        loop {
+            let key = Term::stderr().read_key();
+            let failed = key.is_err();
+            if keys_tx.send(key).is_err() || failed || wanted.recv().is_err() {
+                break;
+            }
+        }
+    });
+    (keys, next_key)
+}
@@ -0,0 +1,641 @@
+//! In-process archive carried by the data frames.
+//!
+//! The payload is the contents of every manifest entry concatenated in
+//! manifest order and cut into numbered `CHUNK_SIZE` pieces. Each piece is
+//! compressed on its own with zstd and travels as one data frame, so neither
+//! side stages anything on disk: the sender reads straight from the source
+//! files and the receiver writes straight into the destination directory.
+//!
+//! Because chunks are independent, the receiver can record which ones it has
+//! in a checkpoint file and ask for only the rest after a dropped link.
+//!
+//! The receiver trusts nothing in the manifest: every path must stay inside
+//! the receive directory without passing through a symlink, only regular
+//! files are ever written, and the file count and total size are capped.
+//! A manifest over the caps is refused as a whole before anything is
+//! written; entries that fail the other checks are rejected one by one and
+//! reported back.
+
+use crate::tunnel::transfer::{Manifest, ManifestEntry, sha256_file};
+use serde::{Deserialize, Serialize};
+use sha2::{Digest, Sha256};
+use std::collections::{BTreeSet, HashSet};
+use std::fs::{File, OpenOptions};
+use std::io::{Read, Seek, SeekFrom, Write};
+use std::path::{Path, PathBuf};
+
+/// Uncompressed size of one data frame.
+pub const CHUNK_SIZE: usize = 1024 * 1024;
+const ZSTD_LEVEL: i32 = 3;
+
+/// Name of the resume checkpoint kept in the receive directory.
+const CHECKPOINT_FILE: &str = ".fling-partial";
+/// How many chunks the receiver writes between checkpoint saves.
+const CHECKPOINT_EVERY: usize = 16;
+
+/// Most files a receiver accepts in one transfer.
+pub const MAX_FILES: usize = 100_000;
+/// Most payload bytes a receiver accepts in one transfer.
+pub const MAX_TOTAL_BYTES: u64 = 256 * 1024 * 1024 * 1024;
+/// Longest accepted manifest path and path component, in bytes.
+const MAX_PATH_LEN: usize = 4096;
+const MAX_NAME_LEN: usize = 255;
+
+/// What the receiver does when a file it is about to write already exists.
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq, clap::ValueEnum)]
+pub enum ConflictPolicy {
+    /// Keep both, saving the new file as `name (1).ext`
+    #[default]
+    Rename,
+    /// Replace the existing file
+    Overwrite,
+    /// Keep the existing file and drop the incoming one
+    Skip,
+    /// Prompt for each conflicting file
+    Ask,
+}
+
+/// What the receiver does with one manifest entry.
+#[derive(Clone, Debug, Serialize, Deserialize)]
+pub enum Target {
+    Write(PathBuf),
+    /// Kept the existing file, per the conflict policy.
+    Skip,
+    /// Refused, with the reason reported back to the sender.
+    Reject(String),
+}
+
+impl Target {
+    pub fn path(&self) -> Option<&Path> {
+        match self {
+            Target::Write(path) => Some(path),
+            _ => None,
+        }
+    }
+}
+
+/// Checks `manifest` against the file count and total size caps, so that an
+/// oversized offer is refused before the receiver walks its entries or
+/// chunks.
+pub fn check_limits(manifest: &Manifest) -> Result<(), String> {
+    if manifest.entries.len() > MAX_FILES {
+        return Err(format!("more than {} files", MAX_FILES));
+    }
+    if manifest.total_bytes > MAX_TOTAL_BYTES {
+        return Err(format!("more than {} GiB in total", MAX_TOTAL_BYTES >> 30));
+    }
+    Ok(())
+}
+
+/// Decides where manifest path `rel` is written under `dir`, applying
+/// `policy` if something is already there.
+///
+/// `ConflictPolicy::Ask` has to be answered beforehand, for the paths
+/// `conflicts` returns; unanswered, it keeps both files.
+pub fn resolve_target(dir: &Path, rel: &str, policy: ConflictPolicy) -> Target {
+    let target = match confine(dir, rel) {
+        Ok(path) => resolve_conflict(path, rel, policy),
+        Err(reason) => Target::Reject(reason),
+    };
+    if let Target::Reject(reason) = &target {
+        eprintln!("[Receiver] Rejected '{}': {}", rel, reason);
+    }
+    target
+}
+
+/// The manifest paths that would land on something already in `dir`: what
+/// `ConflictPolicy::Ask` asks about before `Unpacker::open`. None when an
+/// interrupted attempt at the same transfer left a checkpoint, which has
+/// settled them already.
+pub fn conflicts(manifest: &Manifest, dir: &Path) -> Vec<String> {
+    if load_checkpoint(manifest, dir).is_some() {
+        return Vec::new();
+    }
+    let mut seen = HashSet::new();
+    manifest
+        .entries
+        .iter()
+        .map(|entry| entry.path.as_str())
+        .filter(|rel| seen.insert(*rel))
+        .filter(|rel| confine(dir, rel).is_ok_and(|path| std::fs::symlink_metadata(path).is_ok()))
+        .map(str::to_string)
+        .collect()
+}
+
+fn resolve_conflict(path: PathBuf, rel: &str, policy: ConflictPolicy) -> Target {
+    let Ok(existing) = std::fs::symlink_metadata(&path) else {
+        return Target::Write(path);
+    };
+
+    match policy {
+        // Never write through a symlink, FIFO or device that is already there.
+        ConflictPolicy::Overwrite if !existing.is_file() => {
+            Target::Reject("existing entry is not a regular file".into())
+        }
+        ConflictPolicy::Overwrite => {
+            eprintln!("[Receiver] Overwriting '{}'", rel);
+            Target::Write(path)
+        }
+        ConflictPolicy::Skip => {
+            eprintln!("[Receiver] Skipping '{}': already exists", rel);
+            Target::Skip
+        }
+        ConflictPolicy::Rename | ConflictPolicy::Ask => {
+            let renamed = free_name(&path);
+            eprintln!("[Receiver] '{}' exists, saving as '{}'", rel, renamed.display());
+            Target::Write(renamed)
+        }
+    }
+}
+
+/// Maps manifest path `rel` into `dir`, refusing anything that could land
+/// outside it: absolute paths, `.` and `..`, and parents that already exist
+/// as symlinks or non-directories.
+fn confine(dir: &Path, rel: &str) -> Result<PathBuf, String> {
+    if rel.is_empty() {
+        return Err("empty path".into());
+    }
+    if rel.len() > MAX_PATH_LEN {
+        return Err("path too long".into());
+    }
+    if rel.contains(['\0', '\\']) {
+        return Err("path contains NUL or backslash".into());
+    }
+
+    let components: Vec<&str> = rel.split('/').collect();
+    for component in &components {
+        match *component {
+            "" => return Err("absolute path or empty component".into()),
+            "." | ".." => return Err("path escapes the receive directory".into()),
+            name if name.len() > MAX_NAME_LEN => return Err("file name too long".into()),
+            _ => {}
+        }
+    }
+    if components[0] == CHECKPOINT_FILE || components[0].starts_with(&format!("{}.", CHECKPOINT_FILE)) {
+        return Err("reserved name".into());
+    }
+
+    let mut path = dir.to_path_buf();
+    for parent in &components[..components.len() - 1] {
+        path.push(parent);
+        match std::fs::symlink_metadata(&path) {
+            Ok(meta) if meta.file_type().is_symlink() => {
+                return Err(format!("'{}' is a symlink", parent));
+            }
+            Ok(meta) if !meta.is_dir() => {
+                return Err(format!("'{}' is not a directory", parent));
+            }
+            _ => {}
+        }
+    }
+    path.push(components[components.len() - 1]);
+    Ok(path)
+}
+
+/// First `name (n).ext` next to `path` that does not exist yet.
+fn free_name(path: &Path) -> PathBuf {
+    let stem = path.file_stem().unwrap_or_default().to_string_lossy();
+    let ext = path
+        .extension()
+        .map(|ext| format!(".{}", ext.to_string_lossy()))
+        .unwrap_or_default();
+    (1..)
+        .map(|n| path.with_file_name(format!("{} ({}){}", stem, n, ext)))
+        .find(|candidate| std::fs::symlink_metadata(candidate).is_err())
+        .expect("unbounded range")
+}
+
+/// Files to send: the manifest plus where each entry lives on this machine.
+#[derive(Debug)]
+pub struct Archive {
+    pub manifest: Manifest,
+    sources: Vec<PathBuf>,
+}
+
+impl Archive {
+    /// Walks every root and describes each regular file under it. Paths are
+    /// recorded relative to the parent of their root, so sending `docs/`
+    /// yields entries such as `docs/a.txt`.
+    pub fn build<P: AsRef<Path>>(roots: &[P]) -> Result<Self, String> {
+        let mut entries = Vec::new();
+        let mut sources = Vec::new();
+
+        for root in roots {
+            let root = root.as_ref();
+            let root = std::fs::canonicalize(root)
+                .map_err(|e| format!("Cannot resolve '{}': {}", root.display(), e))?;
+            let base = root
+                .file_name()
+                .ok_or_else(|| format!("Cannot send '{}'", root.display()))?;
+            collect_entries(&root, Path::new(base), &mut entries, &mut sources)?;
+        }
+
+        let mut seen = std::collections::HashSet::new();
+        if let Some(clash) = entries.iter().find(|e| !seen.insert(e.path.as_str())) {
+            return Err(format!("Two items would both be received as '{}'", clash.path));
+        }
+        if entries.is_empty() {
+            return Err("Nothing to send: no regular files found".into());
+        }
+
+        let total_bytes = entries.iter().map(|e| e.size).sum();
+        Ok(Self { manifest: Manifest { entries, total_bytes, streamed: false }, sources })
+    }
+
+    pub fn chunk_count(&self) -> u64 {
+        chunk_count(&self.manifest)
+    }
+
+    /// Uncompressed size of the given chunks.
+    pub fn bytes_in_chunks(&self, indices: &[u64]) -> u64 {
+        indices
+            .iter()
+            .map(|&index| chunk_len(&self.manifest, index) as u64)
+            .sum()
+    }
+
+    /// Reads and compresses chunk `index`, returning it with its uncompressed
+    /// length.
+    pub fn read_chunk(&self, index: u64) -> Result<(Vec<u8>, usize), String> {
+        let mut raw = vec![0u8; chunk_len(&self.manifest, index)];
+        for (entry, file_offset, range) in segments(&self.manifest, index) {
+            let source = &self.sources[entry];
+            let mut file = File::open(source)
+                .map_err(|e| format!("Cannot open '{}': {}", source.display(), e))?;
+            file.seek(SeekFrom::Start(file_offset))
+                .and_then(|_| file.read_exact(&mut raw[range]))
+                .map_err(|e| {
+                    format!("'{}' changed while it was being sent: {}", source.display(), e)
+                })?;
+        }
+        Ok((compress_chunk(&raw)?, raw.len()))
+    }
+}
+
+/// Compresses one chunk for a data frame.
+pub fn compress_chunk(raw: &[u8]) -> Result<Vec<u8>, String> {
+    zstd::bulk::compress(raw, ZSTD_LEVEL).map_err(|e| format!("Compression failed: {}", e))
+}
+
+/// Reverses `compress_chunk`, refusing anything larger than `CHUNK_SIZE`.
+pub fn decompress_chunk(compressed: &[u8]) -> Result<Vec<u8>, String> {
+    zstd::bulk::decompress(compressed, CHUNK_SIZE)
+        .map_err(|e| format!("Decompression failed: {}", e))
+}
+
+fn collect_entries(
+    path: &Path,
+    rel: &Path,
+    entries: &mut Vec<ManifestEntry>,
+    sources: &mut Vec<PathBuf>,
+) -> Result<(), String> {
+    let meta = std::fs::symlink_metadata(path)
+        .map_err(|e| format!("Cannot stat '{}': {}", path.display(), e))?;
+
+    if meta.is_dir() {
+        let mut children: Vec<_> = std::fs::read_dir(path)
+            .map_err(|e| format!("Cannot read '{}': {}", path.display(), e))?
+            .filter_map(|entry| entry.ok())
+            .collect();
+        children.sort_by_key(|entry| entry.file_name());
+        for child in children {
+            collect_entries(&child.path(), &rel.join(child.file_name()), entries, sources)?;
+        }
+    } else if meta.is_file() {
+        entries.push(ManifestEntry {
+            path: rel
+                .components()
+                .map(|c| c.as_os_str().to_string_lossy())
+                .collect::<Vec<_>>()
+                .join("/"),
+            size: meta.len(),
+            mode: file_mode(&meta),
+            sha256: sha256_file(path)?,
+        });
+        sources.push(path.to_path_buf());
+    }
+    Ok(())
+}
+
+#[cfg(unix)]
+fn file_mode(meta: &std::fs::Metadata) -> u32 {
+    use std::os::unix::fs::PermissionsExt;
+    meta.permissions().mode() & 0o7777
+}
+
+fn chunk_count(manifest: &Manifest) -> u64 {
+    manifest.total_bytes.div_ceil(CHUNK_SIZE as u64)
+}
+
+fn chunk_len(manifest: &Manifest, index: u64) -> usize {
+    let start = index * CHUNK_SIZE as u64;
+    manifest.total_bytes.saturating_sub(start).min(CHUNK_SIZE as u64) as usize
+}
+
+/// The chunks that carry no byte of a file being written, as sorted
+/// half-open `[start, end)` ranges: the gaps between the chunk spans of the
+/// written files.
+fn unneeded_ranges(manifest: &Manifest, targets: &[Target]) -> Vec<(u64, u64)> {
+    let chunk = CHUNK_SIZE as u64;
+    let mut ranges = Vec::new();
+    let mut next = 0u64;
+    let mut offset = 0u64;
+    for (entry, target) in manifest.entries.iter().zip(targets) {
+        if target.path().is_some() && entry.size > 0 {
+            let first = offset / chunk;
+            if first > next {
+                ranges.push((next, first));
+            }
+            next = next.max((offset + entry.size).div_ceil(chunk));
+        }
+        offset += entry.size;
+    }
+    if chunk_count(manifest) > next {
+        ranges.push((next, chunk_count(manifest)));
+    }
+    ranges
+}
+
+/// Splits chunk `index` into per-file pieces: `(entry, offset in that file,
+/// range within the chunk)`.
+fn segments(manifest: &Manifest, index: u64) -> Vec<(usize, u64, std::ops::Range<usize>)> {
+    let chunk_start = index * CHUNK_SIZE as u64;
+    let chunk_end = chunk_start + chunk_len(manifest, index) as u64;
+    let mut pieces = Vec::new();
+    let mut entry_start = 0u64;
+
+    for (i, entry) in manifest.entries.iter().enumerate() {
+        let entry_end = entry_start + entry.size;
+        let start = entry_start.max(chunk_start);
+        let end = entry_end.min(chunk_end);
+        if start < end {
+            pieces.push((
+                i,
+                start - entry_start,
+                (start - chunk_start) as usize..(end - chunk_start) as usize,
+            ));
+        }
+        if entry_end >= chunk_end {
+            break;
+        }
+        entry_start = entry_end;
+    }
+    pieces
+}
+
+/// The checkpoint an interrupted attempt at `manifest` left in `dir`, if
+/// its files are all still there.
+fn load_checkpoint(manifest: &Manifest, dir: &Path) -> Option<Checkpoint> {
+    let data = std::fs::read(dir.join(CHECKPOINT_FILE)).ok()?;
+    let checkpoint: Checkpoint = serde_json::from_slice(&data).ok()?;
+    if checkpoint.transfer_id != transfer_id(manifest)
+        || checkpoint.targets.len() != manifest.entries.len()
+    {
+        return None;
+    }
+    // Only trust the checkpoint if every file is still there, no larger
+    // than the chunks can have made it.
+    let intact = manifest.entries.iter().zip(&checkpoint.targets).all(|(entry, target)| {
+        target.path().is_none_or(|path| {
+            std::fs::symlink_metadata(path).is_ok_and(|m| m.is_file() && m.len() <= entry.size)
+        })
+    });
+    intact.then_some(checkpoint)
+}
+
+/// Identifies a transfer so a checkpoint is only reused for the same offer.
+fn transfer_id(manifest: &Manifest) -> String {
+    let encoded = serde_json::to_vec(manifest).unwrap_or_default();
+    crate::crypto::crypto::key_to_hex_string(&Sha256::digest(encoded))
+}
+
+#[derive(Serialize, Deserialize)]
+struct Checkpoint {
+    transfer_id: String,
+    chunks: Vec<u64>,
+    /// What happens to each entry, as decided when the transfer started.
+    #[serde(default)]
+    targets: Vec<Target>,
+}
+
+/// Writes received chunks into the destination directory and keeps track of
+/// which ones have arrived.
+pub struct Unpacker<'a> {
+    manifest: &'a Manifest,
+    dir: PathBuf,
+    targets: Vec<Target>,
+    have: BTreeSet<u64>,
+    /// Chunks that only carry skipped or rejected files and need not be sent
+    /// at all, as sorted half-open `[start, end)` ranges.
+    unneeded: Vec<(u64, u64)>,
+    unsaved: usize,
+}
+
+impl<'a> Unpacker<'a> {
+    /// Prepares `dir` for `manifest`. If a checkpoint from an interrupted
+    /// attempt at the same transfer is present, its chunks and file targets
+    /// are kept; otherwise `policy` settles the conflict for each path and
+    /// each file is created, empty. A manifest over the caps is refused, see
+    /// `check_limits`.
+    pub fn open(
+        manifest: &'a Manifest,
+        dir: &Path,
+        policy: impl Fn(&str) -> ConflictPolicy,
+    ) -> Result<Self, String> {
+        check_limits(manifest).map_err(|reason| format!("Transfer refused: {}", reason))?;
+        let declared = manifest.entries.iter().try_fold(0u64, |sum, e| sum.checked_add(e.size));
+        if declared != Some(manifest.total_bytes) {
+            return Err("Manifest sizes do not add up to its total".into());
+        }
+        std::fs::create_dir_all(dir)
+            .map_err(|e| format!("Cannot create '{}': {}", dir.display(), e))?;
+        let mut unpacker = Self {
+            manifest,
+            dir: dir.to_path_buf(),
+            targets: Vec::new(),
+            have: BTreeSet::new(),
+            unneeded: Vec::new(),
+            unsaved: 0,
+        };
+
+        if let Some(checkpoint) = load_checkpoint(manifest, dir) {
+            unpacker.have = checkpoint.chunks.into_iter().collect();
+            unpacker.targets = checkpoint.targets;
+        } else {
+            unpacker.prepare_files(policy);
+        }
+        unpacker.unneeded = unneeded_ranges(manifest, &unpacker.targets);
+        Ok(unpacker)
+    }
+
+    fn prepare_files(&mut self, policy: impl Fn(&str) -> ConflictPolicy) {
+        let mut seen = HashSet::new();
+
+        for entry in &self.manifest.entries {
+            let target = if seen.insert(entry.path.as_str()) {
+                resolve_target(&self.dir, &entry.path, policy(&entry.path))
+            } else {
+                eprintln!("[Receiver] Rejected '{}': duplicate entry", entry.path);
+                Target::Reject("duplicate entry".into())
+            };
+
+            let target = match target {
+                Target::Write(path) => match create_file(&path) {
+                    Ok(_) => Target::Write(path),
+                    Err(reason) => {
+                        eprintln!("[Receiver] Rejected '{}': {}", entry.path, reason);
+                        Target::Reject(reason)
+                    }
+                },
+                other => other,
+            };
+            self.targets.push(target);
+        }
+    }
+
+    /// Uncompressed bytes already on disk.
+    pub fn received_bytes(&self) -> u64 {
+        self.have.iter().map(|&index| chunk_len(self.manifest, index) as u64).sum()
+    }
+
+    /// Uncompressed bytes that will not be sent because they only carry
+    /// skipped files.
+    pub fn skipped_bytes(&self) -> u64 {
+        let offset = |index: u64| (index * CHUNK_SIZE as u64).min(self.manifest.total_bytes);
+        self.unneeded.iter().map(|&(start, end)| offset(end) - offset(start)).sum()
+    }
+
+    /// The chunks the sender can leave out, as half-open `[start, end)`
+    /// ranges: those already on disk and those carrying only skipped files.
+    pub fn have_ranges(&self) -> Vec<(u64, u64)> {
+        let mut all: Vec<(u64, u64)> = self.have.iter().map(|&index| (index, index + 1)).collect();
+        all.extend(&self.unneeded);
+        all.sort_unstable();
+
+        let mut ranges: Vec<(u64, u64)> = Vec::new();
+        for (start, end) in all {
+            match ranges.last_mut() {
+                Some((_, last_end)) if *last_end >= start => *last_end = (*last_end).max(end),
+                _ => ranges.push((start, end)),
+            }
+        }
+        ranges
+    }
+
+    /// Decompresses chunk `index` and writes it into place. Returns the
+    /// number of uncompressed bytes it carried.
+    pub fn write_chunk(&mut self, index: u64, compressed: &[u8]) -> Result<usize, String> {
+        if index >= chunk_count(self.manifest) {
+            return Err(format!("Sender sent chunk {} beyond the end of the manifest", index));
+        }
+        let expected = chunk_len(self.manifest, index);
+        let data = decompress_chunk(compressed)?;
+        if data.len() != expected {
+            return Err(format!(
+                "Chunk {} has {} bytes, expected {}",
+                index,
+                data.len(),
+                expected
+            ));
+        }
+
+        for (entry, file_offset, range) in segments(self.manifest, index) {
+            let Some(path) = self.targets[entry].path() else {
+                continue;
+            };
+            let mut file = OpenOptions::new()
+                .write(true)
+                .open(path)
+                .map_err(|e| format!("Cannot open '{}': {}", path.display(), e))?;
+            file.seek(SeekFrom::Start(file_offset))
+                .and_then(|_| file.write_all(&data[range]))
+                .map_err(|e| format!("Write error in '{}': {}", path.display(), e))?;
+        }
+
+        self.have.insert(index);
+        self.unsaved += 1;
+        if self.unsaved >= CHECKPOINT_EVERY {
+            self.checkpoint()?;
+        }
+        Ok(data.len())
+    }
+
+    /// Records the chunks received so far so an interrupted transfer can be
+    /// resumed.
+    pub fn checkpoint(&mut self) -> Result<(), String> {
+        let checkpoint = Checkpoint {
+            transfer_id: transfer_id(self.manifest),
+            chunks: self.have.iter().copied().collect(),
+            targets: self.targets.clone(),
+        };
+        let path = self.dir.join(CHECKPOINT_FILE);
+        let tmp = self.dir.join(format!("{}.tmp", CHECKPOINT_FILE));
+        let data = serde_json::to_vec(&checkpoint)
+            .map_err(|e| format!("Serialize failed: {}", e))?;
+        std::fs::write(&tmp, data)
+            .and_then(|_| std::fs::rename(&tmp, &path))
+            .map_err(|e| format!("Cannot write checkpoint: {}", e))?;
+        self.unsaved = 0;
+        Ok(())
+    }
+
+    /// Checks that every chunk has arrived, applies file modes and drops the
+    /// checkpoint. Returns what happened to each entry.
+    pub fn finish(self) -> Result<Vec<Target>, String> {
+        let total = chunk_count(self.manifest);
+        let missing: Vec<u64> = (0..total)
+            .filter(|index| {
+                !self.have.contains(index)
+                    && !self.unneeded.iter().any(|&(start, end)| (start..end).contains(index))
+            })
+            .collect();
+        if let Some(first) = missing.first() {
+            return Err(format!(
+                "Transfer ended with {} of {} chunks missing (first: {})",
+                missing.len(),
+                total,
+                first
+            ));
+        }
+
+        for (entry, target) in self.manifest.entries.iter().zip(&self.targets) {
+            if let Some(path) = target.path() {
+                set_mode(path, entry.mode)?;
+            }
+        }
+        let _ = std::fs::remove_file(self.dir.join(CHECKPOINT_FILE));
+        Ok(self.targets)
+    }
+}
+
+/// Creates an empty regular file at `path`, with its parents; the chunks
+/// written into it make it grow, so a manifest cannot claim disk space it
+/// never sends. A regular file already there is one the conflict policy
+/// chose to overwrite and is replaced. Problems here are specific to the
+/// entry, so they come back as a reason to reject it rather than abort the
+/// whole transfer.
+pub fn create_file(path: &Path) -> Result<File, String> {
+    if let Some(parent) = path.parent() {
+        std::fs::create_dir_all(parent).map_err(|e| format!("cannot create directory: {}", e))?;
+    }
+    match std::fs::symlink_metadata(path) {
+        Ok(meta) if !meta.is_file() => return Err("existing entry is not a regular file".into()),
+        Ok(_) => std::fs::remove_file(path).map_err(|e| format!("cannot replace file: {}", e))?,
+        Err(_) => {}
+    }
+    // Refuses whatever took its place since, a symlink included, rather than
+    // follow it.
+    OpenOptions::new()
+        .write(true)
+        .create_new(true)
+        .open(path)
+        .map_err(|e| format!("cannot create file: {}", e))
+}
+
+/// Applies the sender's permission bits, minus setuid, setgid and sticky.
+#[cfg(unix)]
+fn set_mode(path: &Path, mode: u32) -> Result<(), String> {
+    use std::os::unix::fs::PermissionsExt;
+    std::fs::set_permissions(path, std::fs::Permissions::from_mode(mode & 0o777))
+        .map_err(|e| format!("Cannot set permissions on '{}': {}", path.display(), e))
+}
@@ -1,7 +0,0 @@
-#[cfg(target_os = "linux")]
-use crate::linux::connection;
-
-#[cfg(target_os = "macos")]
-use crate::macos::connection;
-
-pub use connection::*;
\ No newline at end of file
@@ -1,6 +1,7 @@
-//! WiFi Direct Tunnel Module
-//! 
-//! Handles WiFi Direct network creation and high-speed file transfers
+//! Transfer Tunnel Module
+//!
+//! Handles the encrypted transfer protocol and the chunked archive it
+//! carries. Bringing the network up is left to `platform::LinkProvider`.
 
-pub mod connection;
-pub mod transfer;
\ No newline at end of file
+pub mod archive;
+pub mod transfer;
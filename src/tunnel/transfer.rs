@@ -1,7 +1,1083 @@
-#[cfg(target_os="linux")]
-use crate::linux::transfer;
+//! Fling wire protocol.
+//!
+//! Every message travels in its own encrypted frame (see `crypto::stream`):
+//!
+//! ```text
+//! sender   -> receiver: Hello, Manifest,      Data*, Trailer, <end of stream>
+//! receiver -> sender:                 Resume,               Report, <end of stream>
+//! ```
+//!
+//! The manifest lists every file with its size, mode and SHA-256 so the
+//! receiver can tell a finished transfer from a dropped one and check each
+//! file once it has been written. Data frames carry the numbered,
+//! zstd-compressed chunks produced by `tunnel::archive`. The receiver answers
+//! the manifest with the chunks it already holds from an earlier, interrupted
+//! attempt, and the sender only sends the rest. A receiver that refuses the
+//! whole transfer answers the manifest with a report instead.
+//!
+//! A streamed payload (`fling send -`) has no size or digest up front: its
+//! manifest is marked `streamed`, chunks arrive strictly in order and the
+//! trailer carries the SHA-256. Streamed transfers, and anything received to
+//! stdout, cannot be resumed.
 
-#[cfg(target_os = "macos")]
-use crate::macos::transfer;
+use crate::crypto::stream::{Direction, SecureReader, SecureWriter};
+use crate::tunnel::archive::{
+    Archive, CHUNK_SIZE, ConflictPolicy, MAX_TOTAL_BYTES, Target, Unpacker, check_limits,
+    compress_chunk, conflicts, create_file, decompress_chunk, resolve_target,
+};
+use indicatif::{ProgressBar, ProgressStyle};
+use serde::{Deserialize, Serialize};
+use sha2::{Digest, Sha256};
+use std::collections::HashMap;
+use std::fmt;
+use std::io::{ErrorKind, Read};
+use std::net::SocketAddr;
+use std::path::{Path, PathBuf};
+use std::future::Future;
+use std::time::{Duration, Instant};
+use tokio::io::{AsyncRead, AsyncReadExt, AsyncWrite, AsyncWriteExt};
 
-pub use transfer::*;
\ No newline at end of file
+pub const PROTOCOL_VERSION: u16 = 2;
+
+const TAG_HELLO: u8 = 1;
+const TAG_MANIFEST: u8 = 2;
+const TAG_DATA: u8 = 3;
+const TAG_TRAILER: u8 = 4;
+const TAG_REPORT: u8 = 5;
+const TAG_RESUME: u8 = 6;
+
+/// How long the data phase may make no progress before the link counts as
+/// dropped. A Wi-Fi link that vanishes often leaves the socket hanging
+/// rather than closing it.
+const STALL_TIMEOUT: Duration = Duration::from_secs(30);
+
+/// Why a transfer stopped.
+#[derive(Debug)]
+pub enum TransferError {
+    /// The link dropped mid-transfer. The receiver has checkpointed what it
+    /// got, so a new connection can pick up where this one left off.
+    Interrupted(String),
+    /// Anything a retry will not fix: bad data, failed verification, local
+    /// I/O errors.
+    Fatal(String),
+}
+
+impl fmt::Display for TransferError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            TransferError::Interrupted(msg) => write!(f, "connection lost: {}", msg),
+            TransferError::Fatal(msg) => f.write_str(msg),
+        }
+    }
+}
+
+impl From<String> for TransferError {
+    fn from(msg: String) -> Self {
+        TransferError::Fatal(msg)
+    }
+}
+
+impl From<std::io::Error> for TransferError {
+    fn from(e: std::io::Error) -> Self {
+        match e.kind() {
+            ErrorKind::UnexpectedEof
+            | ErrorKind::ConnectionReset
+            | ErrorKind::ConnectionAborted
+            | ErrorKind::ConnectionRefused
+            | ErrorKind::BrokenPipe
+            | ErrorKind::NotConnected
+            | ErrorKind::TimedOut
+            | ErrorKind::HostUnreachable
+            | ErrorKind::NetworkUnreachable => TransferError::Interrupted(e.to_string()),
+            _ => TransferError::Fatal(e.to_string()),
+        }
+    }
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct ManifestEntry {
+    /// Path relative to the receive directory, `/`-separated.
+    pub path: String,
+    pub size: u64,
+    pub mode: u32,
+    /// Hex-encoded SHA-256 of the file contents.
+    pub sha256: String,
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct Manifest {
+    pub entries: Vec<ManifestEntry>,
+    pub total_bytes: u64,
+    /// Set for a piped payload: the single entry has no size or digest yet,
+    /// and the trailer carries them instead.
+    #[serde(default)]
+    pub streamed: bool,
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct Trailer {
+    /// Number of uncompressed payload bytes carried by this session's data
+    /// frames.
+    pub payload_bytes: u64,
+    /// Hex-encoded SHA-256 of a streamed payload.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub sha256: Option<String>,
+}
+
+/// What the sender offers.
+pub enum Payload {
+    /// Files and directories on disk, resumable chunk by chunk.
+    Files(Archive),
+    /// A one-shot stream such as stdin, received under `name`. The reader is
+    /// taken by the first session, so a dropped link cannot be retried.
+    Stream {
+        name: String,
+        reader: Option<Box<dyn AsyncRead + Unpin + Send>>,
+    },
+}
+
+impl Payload {
+    /// Builds the payload for `fling send`: `-` alone means stdin, anything
+    /// else is a list of files and directories.
+    pub fn from_args(paths: &[String], name: Option<&str>) -> Result<Self, String> {
+        if paths.iter().any(|path| path == "-") {
+            if paths.len() > 1 {
+                return Err("'-' (stdin) cannot be combined with other paths".into());
+            }
+            return Payload::stdin(name.unwrap_or("stdin"));
+        }
+        if name.is_some() {
+            return Err("--name only applies when sending stdin ('-')".into());
+        }
+        Archive::build(paths).map(Payload::Files)
+    }
+
+    /// Offers stdin, to be saved as `name` on the receiving side.
+    pub fn stdin(name: &str) -> Result<Self, String> {
+        if name.is_empty() || name.contains('/') || name == "." || name == ".." {
+            return Err(format!("Invalid name for piped input: '{}'", name));
+        }
+        Ok(Payload::Stream { name: name.to_string(), reader: Some(Box::new(tokio::io::stdin())) })
+    }
+
+    /// The offer shown to the receiver before it accepts.
+    pub fn offer(&self, sender: &str) -> Offer {
+        match self {
+            Payload::Files(archive) => Offer {
+                sender: sender.to_string(),
+                items: archive.manifest.item_summary(),
+                more: 0,
+                total_bytes: Some(archive.manifest.total_bytes),
+                lan: Vec::new(),
+                port: 0,
+            },
+            Payload::Stream { name, .. } => Offer {
+                sender: sender.to_string(),
+                items: vec![format!("{} (piped input)", name)],
+                more: 0,
+                total_bytes: None,
+                lan: Vec::new(),
+                port: 0,
+            },
+        }
+    }
+
+    pub fn describe(&self) -> String {
+        match self {
+            Payload::Files(archive) => format!("{} file(s)", archive.manifest.entries.len()),
+            Payload::Stream { name, .. } => format!("piped input as '{}'", name),
+        }
+    }
+}
+
+/// Largest encoded offer. GATT attribute values stop at 512 bytes, and the
+/// sealed offer also carries a 16-byte tag.
+const MAX_OFFER_LEN: usize = 480;
+
+/// What the receiver is shown before it accepts a transfer. The sender
+/// serves it, sealed with the session key, over the GATT offer
+/// characteristic.
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct Offer {
+    pub sender: String,
+    /// Top-level items, as in `Manifest::item_summary`.
+    pub items: Vec<String>,
+    /// Items left out to keep the offer within one GATT attribute.
+    pub more: usize,
+    /// `None` for piped input, whose size is only known at the end.
+    pub total_bytes: Option<u64>,
+    /// Where the sender already listens on its current networks. The
+    /// receiver tries these before falling back to the sender's hotspot.
+    #[serde(default)]
+    pub lan: Vec<SocketAddr>,
+    /// The port the sender listens on, on the LAN and on its hotspot.
+    pub port: u16,
+}
+
+impl Offer {
+    /// Encodes the offer, dropping trailing items until it fits.
+    pub fn encode(&self) -> Vec<u8> {
+        let mut offer = self.clone();
+        offer.sender = offer.sender.chars().take(64).collect();
+        loop {
+            let encoded = serde_json::to_vec(&offer).unwrap_or_default();
+            if encoded.len() <= MAX_OFFER_LEN || offer.items.is_empty() {
+                return encoded;
+            }
+            offer.items.pop();
+            offer.more += 1;
+        }
+    }
+
+    pub fn decode(data: &[u8]) -> Result<Self, String> {
+        serde_json::from_slice(data).map_err(|e| format!("Malformed offer: {}", e))
+    }
+}
+
+impl fmt::Display for Offer {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        writeln!(f, "From: {}", self.sender)?;
+        for item in &self.items {
+            writeln!(f, "  → {}", item)?;
+        }
+        if self.more > 0 {
+            writeln!(f, "  … and {} more", self.more)?;
+        }
+        match self.total_bytes {
+            Some(bytes) => write!(f, "Total: {:.2} MB", bytes as f64 / 1_000_000.0),
+            None => write!(f, "Total: unknown (piped input)"),
+        }
+    }
+}
+
+/// Asks the user what to do about one file that already exists, for
+/// `ConflictPolicy::Ask`. It blocks, so it is run off the async runtime.
+pub type AskConflict = fn(&str) -> Result<ConflictPolicy, String>;
+
+/// Where the receiver puts what arrives.
+#[derive(Debug, Clone)]
+pub enum Destination {
+    Dir { path: PathBuf, on_conflict: ConflictPolicy, ask: AskConflict },
+    /// The payload of a single-file transfer, written to stdout.
+    Stdout,
+}
+
+impl Manifest {
+    /// Describes the file being transferred once `offset` payload bytes are
+    /// done, e.g. `[2/5] logs/app.log 40%`.
+    pub fn progress_label(&self, offset: u64) -> String {
+        let mut start = 0u64;
+        for (i, entry) in self.entries.iter().enumerate() {
+            let end = start + entry.size;
+            if entry.size > 0 && offset <= end {
+                let pct = offset.saturating_sub(start) * 100 / entry.size;
+                return format!("[{}/{}] {} {}%", i + 1, self.entries.len(), entry.path, pct);
+            }
+            start = end;
+        }
+        format!("[{0}/{0}] done", self.entries.len())
+    }
+
+    /// One line per top-level item sent, e.g. `a.pdf (1.20 MB)` or
+    /// `logs/ (34 files, 12.00 MB)`.
+    pub fn item_summary(&self) -> Vec<String> {
+        let mut items: Vec<(String, bool, usize, u64)> = Vec::new();
+        for entry in &self.entries {
+            let (name, is_dir) = match entry.path.split_once('/') {
+                Some((dir, _)) => (dir, true),
+                None => (entry.path.as_str(), false),
+            };
+            match items.last_mut() {
+                Some(item) if item.0 == name && item.1 == is_dir => {
+                    item.2 += 1;
+                    item.3 += entry.size;
+                }
+                _ => items.push((name.to_string(), is_dir, 1, entry.size)),
+            }
+        }
+        items
+            .into_iter()
+            .map(|(name, is_dir, files, bytes)| {
+                let mb = bytes as f64 / 1_000_000.0;
+                if is_dir {
+                    format!("{}/ ({} files, {:.2} MB)", name, files, mb)
+                } else {
+                    format!("{} ({:.2} MB)", name, mb)
+                }
+            })
+            .collect()
+    }
+}
+
+/// The receiver's verdict after checking every file against the manifest.
+#[derive(Debug, Clone, Default, Serialize, Deserialize)]
+pub struct Report {
+    pub verified: usize,
+    pub missing: Vec<String>,
+    pub corrupt: Vec<String>,
+    /// Files the receiver kept its own copy of.
+    #[serde(default)]
+    pub skipped: Vec<String>,
+    /// Files the receiver refused to write, with the reason.
+    #[serde(default)]
+    pub rejected: Vec<String>,
+}
+
+pub enum Message {
+    Hello { version: u16 },
+    Manifest(Manifest),
+    /// Chunks the receiver already holds, as half-open `[start, end)` ranges.
+    Resume { have: Vec<(u64, u64)> },
+    Data { index: u64, bytes: Vec<u8> },
+    Trailer(Trailer),
+    Report(Report),
+}
+
+impl Message {
+    pub fn kind(&self) -> &'static str {
+        match self {
+            Message::Hello { .. } => "hello",
+            Message::Manifest(_) => "manifest",
+            Message::Resume { .. } => "resume",
+            Message::Data { .. } => "data",
+            Message::Trailer(_) => "trailer",
+            Message::Report(_) => "report",
+        }
+    }
+
+    pub fn encode(&self) -> Result<Vec<u8>, String> {
+        match self {
+            Message::Hello { version } => {
+                let mut out = vec![TAG_HELLO];
+                out.extend_from_slice(&version.to_be_bytes());
+                Ok(out)
+            }
+            Message::Manifest(manifest) => json(TAG_MANIFEST, manifest),
+            Message::Resume { have } => json(TAG_RESUME, have),
+            Message::Data { index, bytes } => {
+                let mut out = Vec::with_capacity(bytes.len() + 9);
+                out.push(TAG_DATA);
+                out.extend_from_slice(&index.to_be_bytes());
+                out.extend_from_slice(bytes);
+                Ok(out)
+            }
+            Message::Trailer(trailer) => json(TAG_TRAILER, trailer),
+            Message::Report(report) => json(TAG_REPORT, report),
+        }
+    }
+
+    pub fn decode(mut frame: Vec<u8>) -> Result<Self, String> {
+        if frame.is_empty() {
+            return Err("Empty protocol message".into());
+        }
+        let tag = frame[0];
+        let body = &frame[1..];
+        let parse_err = |e: serde_json::Error| format!("Malformed message (type {}): {}", tag, e);
+        match tag {
+            TAG_HELLO => {
+                let version: [u8; 2] = body
+                    .try_into()
+                    .map_err(|_| "Malformed hello message".to_string())?;
+                Ok(Message::Hello { version: u16::from_be_bytes(version) })
+            }
+            TAG_MANIFEST => serde_json::from_slice(body).map(Message::Manifest).map_err(parse_err),
+            TAG_RESUME => serde_json::from_slice(body)
+                .map(|have| Message::Resume { have })
+                .map_err(parse_err),
+            TAG_DATA => {
+                let index: [u8; 8] = body
+                    .get(..8)
+                    .and_then(|b| b.try_into().ok())
+                    .ok_or_else(|| "Malformed data message".to_string())?;
+                frame.drain(..9);
+                Ok(Message::Data { index: u64::from_be_bytes(index), bytes: frame })
+            }
+            TAG_TRAILER => serde_json::from_slice(body).map(Message::Trailer).map_err(parse_err),
+            TAG_REPORT => serde_json::from_slice(body).map(Message::Report).map_err(parse_err),
+            other => Err(format!("Unknown message type {}", other)),
+        }
+    }
+}
+
+fn json<T: Serialize>(tag: u8, value: &T) -> Result<Vec<u8>, String> {
+    let mut out = vec![tag];
+    serde_json::to_writer(&mut out, value).map_err(|e| format!("Serialize failed: {}", e))?;
+    Ok(out)
+}
+
+pub async fn send_message<W: AsyncWrite + Unpin>(
+    writer: &mut SecureWriter<W>,
+    message: &Message,
+) -> Result<(), TransferError> {
+    Ok(writer.write_frame(&message.encode()?).await?)
+}
+
+/// Reads the next message. The end of the encrypted stream is an error here,
+/// since every caller knows which message it expects next.
+pub async fn recv_message<R: AsyncRead + Unpin>(
+    reader: &mut SecureReader<R>,
+) -> Result<Message, TransferError> {
+    match reader.read_frame().await? {
+        Some(frame) => Ok(Message::decode(frame)?),
+        None => Err(TransferError::Fatal("Peer ended the stream unexpectedly".into())),
+    }
+}
+
+/// Receives the hello and manifest that open every transfer.
+pub async fn recv_offer<R: AsyncRead + Unpin>(
+    reader: &mut SecureReader<R>,
+) -> Result<Manifest, TransferError> {
+    match recv_message(reader).await? {
+        Message::Hello { version } if version == PROTOCOL_VERSION => {}
+        Message::Hello { version } => {
+            return Err(format!(
+                "Sender speaks protocol version {}, this build speaks {}",
+                version, PROTOCOL_VERSION
+            )
+            .into());
+        }
+        other => return Err(format!("Expected hello, got {}", other.kind()).into()),
+    }
+    match recv_message(reader).await? {
+        Message::Manifest(manifest) => Ok(manifest),
+        other => Err(format!("Expected manifest, got {}", other.kind()).into()),
+    }
+}
+
+async fn stall_guard<T>(
+    operation: impl Future<Output = Result<T, TransferError>>,
+) -> Result<T, TransferError> {
+    tokio::time::timeout(STALL_TIMEOUT, operation)
+        .await
+        .map_err(|_| {
+            TransferError::Interrupted(format!("no progress for {}s", STALL_TIMEOUT.as_secs()))
+        })?
+}
+
+/// Progress for a payload whose size is not known in advance.
+fn stream_progress(colour: &str) -> ProgressBar {
+    let pb = ProgressBar::new_spinner();
+    pb.set_style(
+        ProgressStyle::with_template(&format!(
+            "[{{elapsed_precise}}] {{spinner:.{}}} {{bytes}} ({{bytes_per_sec}})\n{{msg}}",
+            colour
+        ))
+        .unwrap(),
+    );
+    pb.enable_steady_tick(Duration::from_millis(120));
+    pb
+}
+
+/// A dropped link cannot be resumed once bytes have gone out of or into a
+/// stream, so it is reported as fatal.
+fn no_resume(e: TransferError) -> TransferError {
+    match e {
+        TransferError::Interrupted(msg) => {
+            TransferError::Fatal(format!("connection lost ({}); streamed transfers cannot resume", msg))
+        }
+        other => other,
+    }
+}
+
+fn progress_bar(total: u64, colour: &str) -> ProgressBar {
+    let pb = ProgressBar::new(total);
+    pb.set_style(
+        ProgressStyle::with_template(&format!(
+            "[{{elapsed_precise}}] {{bar:40.{}/blue}} {{bytes}}/{{total_bytes}} ({{bytes_per_sec}})\n{{msg}}",
+            colour
+        ))
+        .unwrap()
+        .progress_chars("##-"),
+    );
+    pb
+}
+
+/// Runs the sender side of one connection, under that connection's `key`
+/// from `crypto::proof`.
+pub async fn send_session<R, W>(
+    read_half: R,
+    write_half: W,
+    payload: &mut Payload,
+    key: &[u8],
+) -> Result<(), TransferError>
+where
+    R: AsyncRead + Unpin,
+    W: AsyncWrite + Unpin,
+{
+    match payload {
+        Payload::Files(archive) => send_archive(read_half, write_half, archive, key).await,
+        Payload::Stream { name, reader } => {
+            let source = reader.take().ok_or_else(|| {
+                TransferError::Fatal("Piped input was consumed by an earlier attempt".into())
+            })?;
+            send_stream(read_half, write_half, name, source, key).await.map_err(no_resume)
+        }
+    }
+}
+
+/// Offers the archive, sends every chunk the receiver is missing and waits
+/// for its verification report.
+async fn send_archive<R, W>(
+    read_half: R,
+    write_half: W,
+    archive: &Archive,
+    key: &[u8],
+) -> Result<(), TransferError>
+where
+    R: AsyncRead + Unpin,
+    W: AsyncWrite + Unpin,
+{
+    let mut writer = SecureWriter::new(write_half, key, Direction::SenderToReceiver)?;
+    let mut reader = SecureReader::new(read_half, key, Direction::ReceiverToSender)?;
+    let manifest = &archive.manifest;
+
+    send_message(&mut writer, &Message::Hello { version: PROTOCOL_VERSION }).await?;
+    send_message(&mut writer, &Message::Manifest(manifest.clone())).await?;
+
+    let have = match recv_message(&mut reader).await? {
+        Message::Resume { have } => have,
+        Message::Report(report) => {
+            return Err(format!("Receiver refused the transfer: {}", report).into());
+        }
+        other => return Err(format!("Expected resume, got {}", other.kind()).into()),
+    };
+    let missing: Vec<u64> = (0..archive.chunk_count())
+        .filter(|index| !have.iter().any(|&(start, end)| (start..end).contains(index)))
+        .collect();
+    let already = archive.chunk_count() - missing.len() as u64;
+    eprintln!("[Sender] Sending {} item(s):", manifest.item_summary().len());
+    for item in manifest.item_summary() {
+        eprintln!("  → {}", item);
+    }
+    if already > 0 {
+        eprintln!(
+            "[Sender] Resuming: receiver already has {} of {} chunks",
+            already,
+            archive.chunk_count()
+        );
+    }
+
+    let pb = progress_bar(manifest.total_bytes, "cyan");
+    pb.set_position(manifest.total_bytes.saturating_sub(archive.bytes_in_chunks(&missing)));
+
+    let mut session_bytes = 0u64;
+    let mut wire_bytes = 0u64;
+    let start = Instant::now();
+
+    for index in missing {
+        let (chunk, raw_len) = archive.read_chunk(index)?;
+        wire_bytes += chunk.len() as u64;
+        stall_guard(send_message(&mut writer, &Message::Data { index, bytes: chunk })).await?;
+        session_bytes += raw_len as u64;
+        pb.inc(raw_len as u64);
+        pb.set_message(manifest.progress_label(index * CHUNK_SIZE as u64 + raw_len as u64));
+    }
+    let trailer = Trailer { payload_bytes: session_bytes, sha256: None };
+    send_message(&mut writer, &Message::Trailer(trailer)).await?;
+    writer.finish().await?;
+
+    let report = match recv_message(&mut reader).await? {
+        Message::Report(report) => report,
+        other => return Err(format!("Expected report, got {}", other.kind()).into()),
+    };
+    if !report.is_ok() {
+        pb.abandon();
+        return Err(format!("Receiver rejected the transfer: {}", report).into());
+    }
+
+    pb.finish_with_message("✅ Transfer complete");
+    eprintln!("[Sender] Receiver report: {}", report);
+
+    let elapsed = start.elapsed().as_secs_f64();
+    let mbps = (session_bytes as f64 * 8.0) / (elapsed * 1_000_000.0);
+    eprintln!(
+        "[Sender] ✅ Sent {:.2} MB ({:.2} MB compressed) in {:.2}s ({:.2} Mbps)",
+        session_bytes as f64 / 1_000_000.0,
+        wire_bytes as f64 / 1_000_000.0,
+        elapsed,
+        mbps
+    );
+    Ok(())
+}
+
+/// Sends `source` chunk by chunk until it ends, then the digest in the
+/// trailer.
+async fn send_stream<R, W>(
+    read_half: R,
+    write_half: W,
+    name: &str,
+    mut source: Box<dyn AsyncRead + Unpin + Send>,
+    key: &[u8],
+) -> Result<(), TransferError>
+where
+    R: AsyncRead + Unpin,
+    W: AsyncWrite + Unpin,
+{
+    let mut writer = SecureWriter::new(write_half, key, Direction::SenderToReceiver)?;
+    let mut reader = SecureReader::new(read_half, key, Direction::ReceiverToSender)?;
+    let manifest = Manifest {
+        entries: vec![ManifestEntry {
+            path: name.to_string(),
+            size: 0,
+            mode: 0o644,
+            sha256: String::new(),
+        }],
+        total_bytes: 0,
+        streamed: true,
+    };
+
+    send_message(&mut writer, &Message::Hello { version: PROTOCOL_VERSION }).await?;
+    send_message(&mut writer, &Message::Manifest(manifest)).await?;
+    match recv_message(&mut reader).await? {
+        Message::Resume { .. } => {}
+        Message::Report(report) => {
+            return Err(format!("Receiver refused the transfer: {}", report).into());
+        }
+        other => return Err(format!("Expected resume, got {}", other.kind()).into()),
+    }
+    eprintln!("[Sender] Streaming piped input as '{}'", name);
+
+    let pb = stream_progress("cyan");
+    let mut hasher = Sha256::new();
+    let mut buffer = vec![0u8; CHUNK_SIZE];
+    let mut session_bytes = 0u64;
+    let mut index = 0u64;
+    let start = Instant::now();
+
+    loop {
+        let len = read_full(&mut source, &mut buffer)
+            .await
+            .map_err(|e| TransferError::Fatal(format!("Failed to read piped input: {}", e)))?;
+        if len == 0 {
+            break;
+        }
+        hasher.update(&buffer[..len]);
+        let bytes = compress_chunk(&buffer[..len])?;
+        stall_guard(send_message(&mut writer, &Message::Data { index, bytes })).await?;
+        index += 1;
+        session_bytes += len as u64;
+        pb.inc(len as u64);
+    }
+    let digest = crate::crypto::crypto::key_to_hex_string(&hasher.finalize());
+    let trailer = Trailer { payload_bytes: session_bytes, sha256: Some(digest) };
+    send_message(&mut writer, &Message::Trailer(trailer)).await?;
+    writer.finish().await?;
+
+    let report = match recv_message(&mut reader).await? {
+        Message::Report(report) => report,
+        other => return Err(format!("Expected report, got {}", other.kind()).into()),
+    };
+    if !report.is_ok() {
+        pb.abandon();
+        return Err(format!("Receiver rejected the transfer: {}", report).into());
+    }
+    pb.finish_with_message("✅ Transfer complete");
+
+    eprintln!(
+        "[Sender] ✅ Streamed {:.2} MB in {:.2}s",
+        session_bytes as f64 / 1_000_000.0,
+        start.elapsed().as_secs_f64()
+    );
+    Ok(())
+}
+
+/// Fills `buffer` from `source`, returning less only at end of input.
+async fn read_full<R: AsyncRead + Unpin>(source: &mut R, buffer: &mut [u8]) -> std::io::Result<usize> {
+    let mut filled = 0;
+    while filled < buffer.len() {
+        let n = source.read(&mut buffer[filled..]).await?;
+        if n == 0 {
+            break;
+        }
+        filled += n;
+    }
+    Ok(filled)
+}
+
+/// Runs the receiver side of one connection, under that connection's `key`
+/// from `crypto::proof`.
+pub async fn receive_session<R, W>(
+    read_half: R,
+    write_half: W,
+    destination: &Destination,
+    key: &[u8],
+) -> Result<(), TransferError>
+where
+    R: AsyncRead + Unpin,
+    W: AsyncWrite + Unpin,
+{
+    let mut reader = SecureReader::new(read_half, key, Direction::SenderToReceiver)?;
+    let writer = SecureWriter::new(write_half, key, Direction::ReceiverToSender)?;
+
+    let manifest = recv_offer(&mut reader).await?;
+    if manifest.streamed {
+        let names: Vec<&str> = manifest.entries.iter().map(|e| e.path.as_str()).collect();
+        eprintln!("[Receiver] Incoming: piped input as '{}'", names.join(", "));
+    } else {
+        eprintln!(
+            "[Receiver] Incoming: {} file(s), {:.2} MB",
+            manifest.entries.len(),
+            manifest.total_bytes as f64 / 1_000_000.0
+        );
+    }
+
+    match destination {
+        Destination::Dir { path, on_conflict, ask } if !manifest.streamed => {
+            receive_into_dir(reader, writer, manifest, path, *on_conflict, *ask).await
+        }
+        _ => receive_stream(reader, writer, manifest, destination).await.map_err(no_resume),
+    }
+}
+
+/// Writes chunks straight into `output_dir`. If the link drops, the progress
+/// so far is checkpointed there and offered to the sender on the next
+/// attempt.
+async fn receive_into_dir<R, W>(
+    mut reader: SecureReader<R>,
+    mut writer: SecureWriter<W>,
+    manifest: Manifest,
+    output_dir: &Path,
+    on_conflict: ConflictPolicy,
+    ask: AskConflict,
+) -> Result<(), TransferError>
+where
+    R: AsyncRead + Unpin,
+    W: AsyncWrite + Unpin,
+{
+    // Answer an oversized offer with the report instead of a resume, before
+    // looking at any of its entries.
+    if let Err(reason) = check_limits(&manifest) {
+        let report = Report {
+            rejected: vec![format!("all {} file(s) ({})", manifest.entries.len(), reason)],
+            ..Default::default()
+        };
+        send_message(&mut writer, &Message::Report(report.clone())).await?;
+        writer.finish().await?;
+        return Err(format!("Refused the transfer: {}", report).into());
+    }
+
+    let answers = settle_conflicts(&manifest, output_dir, on_conflict, ask).await?;
+    let policy = |rel: &str| answers.get(rel).copied().unwrap_or(on_conflict);
+    let mut unpacker = Unpacker::open(&manifest, output_dir, policy)?;
+    let already = unpacker.received_bytes();
+    if already > 0 {
+        eprintln!(
+            "[Receiver] Resuming: {:.2} MB already on disk",
+            already as f64 / 1_000_000.0
+        );
+    }
+    send_message(&mut writer, &Message::Resume { have: unpacker.have_ranges() }).await?;
+
+    let pb = progress_bar(manifest.total_bytes, "green");
+    pb.set_position(already + unpacker.skipped_bytes());
+    let start = Instant::now();
+
+    let result = receive_chunks(&mut reader, &mut unpacker, &manifest, &pb).await;
+    let session_bytes = match result {
+        Ok(bytes) => bytes,
+        Err(e) => {
+            pb.abandon();
+            if let Err(save_err) = unpacker.checkpoint() {
+                eprintln!("[Receiver] Failed to save resume checkpoint: {}", save_err);
+            }
+            return Err(e);
+        }
+    };
+    let targets = unpacker.finish()?;
+    pb.finish_with_message("✅ Files received");
+
+    let elapsed = start.elapsed().as_secs_f64();
+    let mbps = (session_bytes as f64 * 8.0) / (elapsed * 1_000_000.0);
+    eprintln!(
+        "[Receiver] 📦 Received {:.2} MB into '{}' in {:.2}s ({:.2} Mbps)",
+        session_bytes as f64 / 1_000_000.0,
+        output_dir.display(),
+        elapsed,
+        mbps
+    );
+
+    let report = verify_received(&manifest, &targets);
+    send_message(&mut writer, &Message::Report(report.clone())).await?;
+    writer.finish().await?;
+
+    if report.is_ok() {
+        eprintln!("[Receiver] ✅ {}", report);
+        eprintln!("[Receiver] Received {} item(s):", manifest.item_summary().len());
+        for item in manifest.item_summary() {
+            eprintln!("  → {}", item);
+        }
+        Ok(())
+    } else {
+        Err(format!("Verification failed: {}", report).into())
+    }
+}
+
+/// Answers `ConflictPolicy::Ask` for each entry of `manifest` that lands on
+/// something already in `dir`, through `ask` on a blocking thread, so the
+/// runtime keeps going while the user decides. Other policies need no
+/// answers.
+async fn settle_conflicts(
+    manifest: &Manifest,
+    dir: &Path,
+    on_conflict: ConflictPolicy,
+    ask: AskConflict,
+) -> Result<HashMap<String, ConflictPolicy>, TransferError> {
+    if on_conflict != ConflictPolicy::Ask {
+        return Ok(HashMap::new());
+    }
+    let existing = conflicts(manifest, dir);
+    let answers = tokio::task::spawn_blocking(move || {
+        existing
+            .into_iter()
+            .map(|rel| ask(&rel).map(|policy| (rel, policy)))
+            .collect::<Result<HashMap<_, _>, String>>()
+    })
+    .await
+    .map_err(|e| format!("Cannot ask about conflicts: {}", e))??;
+    Ok(answers)
+}
+
+/// Receives a payload that has to arrive in order: a streamed one, or
+/// anything written to stdout. Either way there is exactly one file, checked
+/// against its digest once the trailer arrives.
+async fn receive_stream<R, W>(
+    mut reader: SecureReader<R>,
+    mut writer: SecureWriter<W>,
+    manifest: Manifest,
+    destination: &Destination,
+) -> Result<(), TransferError>
+where
+    R: AsyncRead + Unpin,
+    W: AsyncWrite + Unpin,
+{
+    let entry = match manifest.entries.as_slice() {
+        [entry] => entry,
+        // Answer with the report, as for a rejected file below, so the
+        // sender learns why.
+        entries => {
+            let report = Report {
+                rejected: vec![format!(
+                    "all {} file(s) (only a single file can be received to stdout)",
+                    entries.len()
+                )],
+                ..Default::default()
+            };
+            send_message(&mut writer, &Message::Report(report.clone())).await?;
+            writer.finish().await?;
+            return Err(format!("Refused the transfer: {}", report).into());
+        }
+    };
+
+    let mut skipped = false;
+    let (mut sink, label): (Box<dyn AsyncWrite + Unpin + Send>, String) = match destination {
+        Destination::Stdout => (Box::new(tokio::io::stdout()), "stdout".into()),
+        Destination::Dir { path: dir, on_conflict, ask } => {
+            std::fs::create_dir_all(dir)
+                .map_err(|e| format!("Cannot create '{}': {}", dir.display(), e))?;
+            let answers = settle_conflicts(&manifest, dir, *on_conflict, *ask).await?;
+            let policy = answers.get(&entry.path).copied().unwrap_or(*on_conflict);
+            match resolve_target(dir, &entry.path, policy) {
+                Target::Write(path) => {
+                    let file = create_file(&path)
+                        .map_err(|e| format!("Cannot create '{}': {}", path.display(), e))?;
+                    (Box::new(tokio::fs::File::from_std(file)), format!("'{}'", path.display()))
+                }
+                // A stream cannot be declined part-way, so drain it instead.
+                Target::Skip => {
+                    skipped = true;
+                    (Box::new(tokio::io::sink()), "nowhere (skipped)".into())
+                }
+                // Answer with the report instead of a resume so the sender
+                // learns why before sending anything.
+                Target::Reject(reason) => {
+                    let report = Report {
+                        rejected: vec![format!("{} ({})", entry.path, reason)],
+                        ..Default::default()
+                    };
+                    send_message(&mut writer, &Message::Report(report.clone())).await?;
+                    writer.finish().await?;
+                    return Err(format!("Refused the transfer: {}", report).into());
+                }
+            }
+        }
+    };
+    send_message(&mut writer, &Message::Resume { have: Vec::new() }).await?;
+
+    let pb = if manifest.streamed {
+        stream_progress("green")
+    } else {
+        progress_bar(manifest.total_bytes, "green")
+    };
+    let mut hasher = Sha256::new();
+    let mut session_bytes = 0u64;
+    let mut next = 0u64;
+
+    let trailer = loop {
+        match stall_guard(recv_message(&mut reader)).await? {
+            Message::Data { index, bytes } => {
+                if index != next {
+                    return Err(format!("Chunk {} arrived out of order, expected {}", index, next).into());
+                }
+                let data = decompress_chunk(&bytes)?;
+                if session_bytes + data.len() as u64 > MAX_TOTAL_BYTES {
+                    return Err(format!(
+                        "Piped input exceeds the {} GiB limit",
+                        MAX_TOTAL_BYTES >> 30
+                    )
+                    .into());
+                }
+                hasher.update(&data);
+                sink.write_all(&data)
+                    .await
+                    .map_err(|e| format!("Write error on {}: {}", label, e))?;
+                next += 1;
+                session_bytes += data.len() as u64;
+                pb.inc(data.len() as u64);
+            }
+            Message::Trailer(trailer) => break trailer,
+            other => {
+                return Err(format!("Unexpected {} message during transfer", other.kind()).into());
+            }
+        }
+    };
+    sink.flush().await.map_err(|e| format!("Write error on {}: {}", label, e))?;
+    if reader.read_frame().await?.is_some() {
+        return Err("Unexpected data after the transfer trailer".to_string().into());
+    }
+
+    let (expected_len, expected_digest) = match &trailer.sha256 {
+        Some(digest) if manifest.streamed => (trailer.payload_bytes, digest.as_str()),
+        _ => (entry.size, entry.sha256.as_str()),
+    };
+    let digest = crate::crypto::crypto::key_to_hex_string(&hasher.finalize());
+    let mut report = Report::default();
+    if skipped {
+        report.skipped.push(entry.path.clone());
+    } else if session_bytes == trailer.payload_bytes && session_bytes == expected_len && digest == expected_digest {
+        report.verified = 1;
+    } else {
+        report.corrupt.push(entry.path.clone());
+    }
+    send_message(&mut writer, &Message::Report(report.clone())).await?;
+    writer.finish().await?;
+
+    if report.is_ok() {
+        pb.finish_with_message("✅ Payload received");
+        eprintln!(
+            "[Receiver] ✅ Wrote {:.2} MB to {}",
+            session_bytes as f64 / 1_000_000.0,
+            label
+        );
+        Ok(())
+    } else {
+        pb.abandon();
+        Err(format!("Verification failed: {}", report).into())
+    }
+}
+
+async fn receive_chunks<R: AsyncRead + Unpin>(
+    reader: &mut SecureReader<R>,
+    unpacker: &mut Unpacker<'_>,
+    manifest: &Manifest,
+    pb: &ProgressBar,
+) -> Result<u64, TransferError> {
+    let mut session_bytes = 0u64;
+    let trailer = loop {
+        match stall_guard(recv_message(reader)).await? {
+            Message::Data { index, bytes } => {
+                let raw_len = unpacker.write_chunk(index, &bytes)? as u64;
+                session_bytes += raw_len;
+                pb.inc(raw_len);
+                pb.set_message(manifest.progress_label(index * CHUNK_SIZE as u64 + raw_len));
+            }
+            Message::Trailer(trailer) => break trailer,
+            other => {
+                return Err(format!("Unexpected {} message during transfer", other.kind()).into());
+            }
+        }
+    };
+    if trailer.payload_bytes != session_bytes {
+        return Err(format!(
+            "Transfer incomplete: received {} of {} bytes",
+            session_bytes, trailer.payload_bytes
+        )
+        .into());
+    }
+    if reader.read_frame().await?.is_some() {
+        return Err("Unexpected data after the transfer trailer".to_string().into());
+    }
+    Ok(session_bytes)
+}
+
+pub fn sha256_file(path: &Path) -> Result<String, String> {
+    let mut file = std::fs::File::open(path)
+        .map_err(|e| format!("Cannot open '{}': {}", path.display(), e))?;
+    let mut hasher = Sha256::new();
+    let mut buffer = vec![0u8; 64 * 1024];
+    loop {
+        let n = file
+            .read(&mut buffer)
+            .map_err(|e| format!("Cannot read '{}': {}", path.display(), e))?;
+        if n == 0 {
+            break;
+        }
+        hasher.update(&buffer[..n]);
+    }
+    Ok(crate::crypto::crypto::key_to_hex_string(&hasher.finalize()))
+}
+
+/// Checks every manifest entry against what the receiver did with it.
+pub fn verify_received(manifest: &Manifest, targets: &[Target]) -> Report {
+    let mut report = Report::default();
+    for (entry, target) in manifest.entries.iter().zip(targets) {
+        let path = match target {
+            Target::Write(path) => path,
+            Target::Skip => {
+                report.skipped.push(entry.path.clone());
+                continue;
+            }
+            Target::Reject(reason) => {
+                report.rejected.push(format!("{} ({})", entry.path, reason));
+                continue;
+            }
+        };
+        match std::fs::metadata(path) {
+            Ok(meta) if meta.is_file() => {
+                let intact = meta.len() == entry.size
+                    && sha256_file(path).is_ok_and(|digest| digest == entry.sha256);
+                if intact {
+                    report.verified += 1;
+                } else {
+                    report.corrupt.push(entry.path.clone());
+                }
+            }
+            _ => report.missing.push(entry.path.clone()),
+        }
+    }
+    report
+}
+
+impl Report {
+    pub fn is_ok(&self) -> bool {
+        self.missing.is_empty() && self.corrupt.is_empty() && self.rejected.is_empty()
+    }
+}
+
+impl fmt::Display for Report {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "{} file(s) verified", self.verified)?;
+        if !self.missing.is_empty() {
+            write!(f, ", {} missing: {}", self.missing.len(), self.missing.join(", "))?;
+        }
+        if !self.corrupt.is_empty() {
+            write!(f, ", {} corrupt: {}", self.corrupt.len(), self.corrupt.join(", "))?;
+        }
+        if !self.skipped.is_empty() {
+            write!(f, ", {} skipped: {}", self.skipped.len(), self.skipped.join(", "))?;
+        }
+        if !self.rejected.is_empty() {
+            write!(f, ", {} rejected: {}", self.rejected.len(), self.rejected.join(", "))?;
+        }
+        Ok(())
+    }
+}
@@ -1,18 +1,70 @@
+use crate::tunnel::archive::ConflictPolicy;
 use clap::{Parser, Subcommand};
+use std::path::PathBuf;
 
 #[derive(Parser)]
 #[command(name="fling", author, version, about="Airdrop for *Nix")]
 pub struct Cli{
     #[command(subcommand)]
     pub command: Commands,
+
+    /// Wireless interface to use [default: detected]
+    #[arg(long, value_name="IFACE", global=true)]
+    pub iface: Option<String>,
 }
 
 #[derive(Subcommand, Debug)]
 pub enum Commands {
     Send {
-        #[arg(value_name="FILE")]
-        filepath: String,
+        /// Files and directories to send in one session, or `-` for stdin
+        #[arg(value_name="PATH", required_unless_present="from_list")]
+        paths: Vec<String>,
+
+        /// File name the receiver saves piped input under
+        #[arg(long, value_name="NAME")]
+        name: Option<String>,
+
+        /// Read additional paths from FILE, one per line (`#` starts a comment)
+        #[arg(long, value_name="FILE")]
+        from_list: Option<String>,
+
+        /// Send to the receiver with this name, alias or Bluetooth address instead of asking
+        #[arg(long, value_name="NAME|MAC|ALIAS")]
+        to: Option<String>,
+
+        /// Send to the first receiver found instead of asking
+        #[arg(long, conflicts_with="to")]
+        first: bool,
+
+        /// Wi-Fi channel to host on; 1-14 is 2.4GHz, above that 5GHz [default: detected]
+        #[arg(long, value_name="N", value_parser=clap::value_parser!(u32).range(1..=196))]
+        channel: Option<u32>,
+    },
+    Receive {
+        /// Write the received file to stdout instead of a directory
+        #[arg(long)]
+        stdout: bool,
+
+        /// Directory to save into [default: $XDG_DOWNLOAD_DIR/fling]
+        #[arg(long, value_name="DIR", conflicts_with="stdout")]
+        dir: Option<PathBuf>,
+
+        /// What to do when a received file already exists
+        #[arg(long, value_enum, default_value="rename")]
+        on_conflict: ConflictPolicy,
     },
-    Receive,
 }
 
+
+/// Reads a `--from-list` file: one path per line, blank lines and `#`
+/// comments ignored.
+pub fn read_path_list(list: &str) -> Result<Vec<String>, String> {
+    let contents = std::fs::read_to_string(list)
+        .map_err(|e| format!("Failed to read path list '{}': {}", list, e))?;
+    Ok(contents
+        .lines()
+        .map(str::trim)
+        .filter(|line| !line.is_empty() && !line.starts_with('#'))
+        .map(String::from)
+        .collect())
+}
@@ -0,0 +1,36 @@
+use std::path::PathBuf;
+
+/// Where `fling receive` saves files without `--dir`: a `fling` folder in
+/// the XDG download directory, falling back to `~/Downloads/fling`.
+pub fn default_receive_dir() -> PathBuf {
+    xdg_download_dir()
+        .or_else(|| home_dir().map(|home| home.join("Downloads")))
+        .unwrap_or_else(|| PathBuf::from("."))
+        .join("fling")
+}
+
+fn home_dir() -> Option<PathBuf> {
+    std::env::var_os("HOME").filter(|h| !h.is_empty()).map(PathBuf::from)
+}
+
+/// `$XDG_DOWNLOAD_DIR`, or its entry in `user-dirs.dirs` when the variable
+/// is not exported (the usual case).
+fn xdg_download_dir() -> Option<PathBuf> {
+    if let Some(dir) = std::env::var_os("XDG_DOWNLOAD_DIR").filter(|d| !d.is_empty()) {
+        return Some(PathBuf::from(dir));
+    }
+
+    let config = std::env::var_os("XDG_CONFIG_HOME")
+        .filter(|c| !c.is_empty())
+        .map(PathBuf::from)
+        .or_else(|| home_dir().map(|home| home.join(".config")))?;
+    let contents = std::fs::read_to_string(config.join("user-dirs.dirs")).ok()?;
+
+    contents.lines().find_map(|line| {
+        let value = line.trim().strip_prefix("XDG_DOWNLOAD_DIR=")?.trim_matches('"');
+        match value.strip_prefix("$HOME") {
+            Some(rest) => Some(home_dir()?.join(rest.trim_start_matches('/'))),
+            None => Some(PathBuf::from(value)),
+        }
+    })
+}
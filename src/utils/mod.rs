@@ -1,2 +1,5 @@
 pub mod cli;
 
+pub mod dirs;
+
+pub mod net;
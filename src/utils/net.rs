@@ -0,0 +1,113 @@
+use crate::crypto::proof::{verify_receiver, verify_sender};
+use crate::tunnel::transfer::TransferError;
+use futures::future::select_all;
+use std::net::{IpAddr, SocketAddr};
+use std::time::Duration;
+use tokio::net::{TcpListener, TcpStream};
+use tokio::time::{Instant, sleep, timeout, timeout_at};
+
+/// At most this many LAN addresses go into an offer.
+const MAX_LAN_ADDRS: usize = 4;
+/// How long one connection attempt may take.
+const CONNECT_TIMEOUT: Duration = Duration::from_secs(3);
+
+/// This machine's addresses on the networks it is already connected to,
+/// with `port`, as a receiver on the same network could reach them.
+///
+/// Loopback and link-local addresses are left out, and so is every address
+/// on the interfaces in `hotspots`, the ones fling itself runs a hotspot on.
+pub fn lan_addresses(port: u16, hotspots: &[String]) -> Vec<SocketAddr> {
+    let interfaces = match if_addrs::get_if_addrs() {
+        Ok(interfaces) => interfaces,
+        Err(e) => {
+            eprintln!("[LAN] Cannot list network interfaces: {}", e);
+            return Vec::new();
+        }
+    };
+
+    let mut addrs: Vec<SocketAddr> = interfaces
+        .into_iter()
+        .filter(|interface| !hotspots.contains(&interface.name))
+        .map(|interface| interface.ip())
+        .filter(|ip| match ip {
+            IpAddr::V4(v4) => !v4.is_loopback() && !v4.is_link_local(),
+            IpAddr::V6(v6) => !v6.is_loopback() && !v6.is_unicast_link_local(),
+        })
+        .map(|ip| SocketAddr::new(ip, port))
+        .collect();
+    // IPv4 first: it is what a shared office or home network most likely has.
+    addrs.sort_by_key(|addr| addr.is_ipv6());
+    addrs.dedup();
+    addrs.truncate(MAX_LAN_ADDRS);
+    addrs
+}
+
+/// Connects to the first of `peers` that answers and proves it holds `key`,
+/// going round them until `wait` runs out. Returns the stream with the key
+/// for its session, which is never `key` itself.
+pub async fn connect_verified(
+    peers: &[SocketAddr],
+    wait: Duration,
+    key: &[u8],
+) -> Result<(TcpStream, Vec<u8>), TransferError> {
+    if peers.is_empty() {
+        return Err(TransferError::Interrupted("no address to connect to".into()));
+    }
+
+    let deadline = Instant::now() + wait;
+    loop {
+        let mut last_error = String::new();
+        for peer in peers {
+            let remaining = deadline.saturating_duration_since(Instant::now());
+            match timeout(remaining.min(CONNECT_TIMEOUT), TcpStream::connect(peer)).await {
+                Ok(Ok(mut stream)) => match verify_sender(&mut stream, key).await {
+                    Ok(connection_key) => {
+                        eprintln!("[Receiver] Connected to sender at {}", peer);
+                        return Ok((stream, connection_key));
+                    }
+                    Err(e) => {
+                        eprintln!("[Receiver] {} is not the sender: {}", peer, e);
+                        last_error = format!("{}: {}", peer, e);
+                    }
+                },
+                Ok(Err(e)) => last_error = format!("{}: {}", peer, e),
+                Err(_) => last_error = format!("{}: timed out", peer),
+            }
+        }
+
+        if Instant::now() + Duration::from_secs(1) >= deadline {
+            return Err(TransferError::Interrupted(format!("Failed to connect ({})", last_error)));
+        }
+        eprintln!("[Receiver] Sender not reachable yet ({}), retrying...", last_error);
+        sleep(Duration::from_secs(1)).await;
+    }
+}
+
+/// Accepts connections on any of `listeners` until one proves it holds
+/// `key`, turning the others away, or `wait` runs out. Returns the stream
+/// and its peer with the key for its session, as `connect_verified` does.
+pub async fn accept_verified(
+    listeners: &[&TcpListener],
+    key: &[u8],
+    wait: Duration,
+) -> Result<(TcpStream, SocketAddr, Vec<u8>), TransferError> {
+    if listeners.is_empty() {
+        return Err(TransferError::Fatal("Not listening for the receiver".into()));
+    }
+
+    let deadline = Instant::now() + wait;
+    loop {
+        let accepts = listeners.iter().map(|listener| Box::pin(listener.accept()));
+        let (accepted, ..) = timeout_at(deadline, select_all(accepts)).await.map_err(|_| {
+            TransferError::Interrupted(format!(
+                "no receiver proved the session key within {}s",
+                wait.as_secs()
+            ))
+        })?;
+        let (mut stream, addr) = accepted?;
+        match verify_receiver(&mut stream, key).await {
+            Ok(connection_key) => return Ok((stream, addr, connection_key)),
+            Err(e) => eprintln!("[Sender] Turned away {}: {}", addr, e),
+        }
+    }
+}
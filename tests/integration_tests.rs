@@ -0,0 +1,336 @@
+//! Runs the sender and receiver FSMs against each other over the loopback
+//! backend.
+
+use fling::fsm::receiver_fsm::{ReceiverState, start_receiver_fsm};
+use fling::fsm::sender_fsm::{SenderState, start_sender_fsm};
+use fling::platform::gatt::{self, ReceiverAdvert};
+use fling::platform::Target;
+use fling::platform::loopback::{self, Lan, Options};
+use fling::tunnel::archive::{self, ConflictPolicy, Unpacker};
+use fling::tunnel::transfer::{Destination, Manifest, ManifestEntry};
+use rand::RngCore;
+use std::fs;
+use std::path::Path;
+use std::time::Duration;
+use tempfile::TempDir;
+
+/// A source tree with a file larger than one chunk and a nested directory.
+fn source_tree() -> (TempDir, Vec<String>) {
+    let dir = tempfile::tempdir().unwrap();
+    let mut big = vec![0u8; 3 * 1024 * 1024 + 17];
+    rand::thread_rng().fill_bytes(&mut big);
+    fs::write(dir.path().join("big.bin"), &big).unwrap();
+    fs::create_dir_all(dir.path().join("notes/deep")).unwrap();
+    fs::write(dir.path().join("notes/a.txt"), "alpha\n").unwrap();
+    fs::write(dir.path().join("notes/deep/b.txt"), "beta\n").unwrap();
+
+    let paths = ["big.bin", "notes"]
+        .iter()
+        .map(|p| dir.path().join(p).to_string_lossy().into_owned())
+        .collect();
+    (dir, paths)
+}
+
+struct Outcome {
+    sent: SenderState,
+    received: ReceiverState<loopback::LoopbackPairing>,
+    /// Whether the sender had to bring up its network.
+    hosted: bool,
+}
+
+async fn run(options: Options, paths: &[String], out: &Path) -> Outcome {
+    run_to(&Target::Ask, options, paths, out).await
+}
+
+async fn run_to(target: &Target, options: Options, paths: &[String], out: &Path) -> Outcome {
+    let (sender, receiver) = loopback::pair(options).unwrap();
+    let destination = Destination::Dir {
+        path: out.to_path_buf(),
+        on_conflict: ConflictPolicy::Rename,
+        ask: |_| Ok(ConflictPolicy::Rename),
+    };
+    let (sent, received) = tokio::join!(
+        start_sender_fsm(&sender, paths, None, target),
+        start_receiver_fsm(&receiver, destination),
+    );
+    Outcome { sent, received, hosted: sender.link.hosted() }
+}
+
+fn assert_same_tree(src: &Path, out: &Path) {
+    for rel in ["big.bin", "notes/a.txt", "notes/deep/b.txt"] {
+        assert_eq!(fs::read(src.join(rel)).unwrap(), fs::read(out.join(rel)).unwrap(), "{}", rel);
+    }
+}
+
+fn files_in(dir: &Path) -> usize {
+    fs::read_dir(dir).map(|entries| entries.count()).unwrap_or(0)
+}
+
+#[tokio::test]
+async fn transfers_files_and_directories() {
+    let (src, paths) = source_tree();
+    let out = tempfile::tempdir().unwrap();
+
+    let Outcome { sent, received, hosted } = run(Options::default(), &paths, out.path()).await;
+
+    assert!(matches!(sent, SenderState::SendSuccess), "sender ended in {:?}", sent);
+    assert!(matches!(received, ReceiverState::ReceiveSuccess), "receiver ended in {:?}", received);
+    assert!(hosted);
+    assert_same_tree(src.path(), out.path());
+}
+
+#[tokio::test]
+async fn shared_network_skips_the_hotspot() {
+    let (src, paths) = source_tree();
+    let out = tempfile::tempdir().unwrap();
+    let options = Options { lan: Lan::Shared, ..Options::default() };
+
+    let Outcome { sent, received, hosted } = run(options, &paths, out.path()).await;
+
+    assert!(matches!(sent, SenderState::SendSuccess), "sender ended in {:?}", sent);
+    assert!(matches!(received, ReceiverState::ReceiveSuccess), "receiver ended in {:?}", received);
+    assert!(!hosted, "the sender brought up a hotspot despite the shared network");
+    assert_same_tree(src.path(), out.path());
+}
+
+#[tokio::test]
+async fn unreachable_lan_falls_back_to_the_hotspot() {
+    let (src, paths) = source_tree();
+    let out = tempfile::tempdir().unwrap();
+    let options = Options { timeout: Duration::from_secs(2), lan: Lan::Unreachable, ..Options::default() };
+
+    let Outcome { sent, received, hosted } = run(options, &paths, out.path()).await;
+
+    assert!(matches!(sent, SenderState::SendSuccess), "sender ended in {:?}", sent);
+    assert!(matches!(received, ReceiverState::ReceiveSuccess), "receiver ended in {:?}", received);
+    assert!(hosted);
+    assert_same_tree(src.path(), out.path());
+}
+
+#[tokio::test]
+async fn impostor_on_the_lan_is_skipped() {
+    let (src, paths) = source_tree();
+    let out = tempfile::tempdir().unwrap();
+    let options = Options { timeout: Duration::from_secs(2), lan: Lan::Impostor, ..Options::default() };
+
+    let Outcome { sent, received, hosted } = run(options, &paths, out.path()).await;
+
+    assert!(matches!(sent, SenderState::SendSuccess), "sender ended in {:?}", sent);
+    assert!(matches!(received, ReceiverState::ReceiveSuccess), "receiver ended in {:?}", received);
+    assert!(hosted);
+    assert_same_tree(src.path(), out.path());
+}
+
+#[tokio::test]
+async fn stranger_without_the_key_is_turned_away() {
+    let (src, paths) = source_tree();
+    let out = tempfile::tempdir().unwrap();
+    let options = Options { stranger: true, ..Options::default() };
+
+    let Outcome { sent, received, .. } = run(options, &paths, out.path()).await;
+
+    assert!(matches!(sent, SenderState::SendSuccess), "sender ended in {:?}", sent);
+    assert!(matches!(received, ReceiverState::ReceiveSuccess), "receiver ended in {:?}", received);
+    assert_same_tree(src.path(), out.path());
+}
+
+#[tokio::test]
+async fn target_is_found_by_name_or_address_without_asking() {
+    let (src, paths) = source_tree();
+    for target in [
+        Target::Named("LOOPBACK-receiver".into()),
+        Target::Named("02:00:00:00:00:01".into()),
+        Target::First,
+    ] {
+        let out = tempfile::tempdir().unwrap();
+
+        let Outcome { sent, received, .. } = run_to(&target, Options::default(), &paths, out.path()).await;
+
+        assert!(matches!(sent, SenderState::SendSuccess), "{:?}: sender ended in {:?}", target, sent);
+        assert!(matches!(received, ReceiverState::ReceiveSuccess), "{:?}: receiver ended in {:?}", target, received);
+        assert_same_tree(src.path(), out.path());
+    }
+}
+
+#[tokio::test]
+async fn unknown_target_finds_no_device() {
+    let (_src, paths) = source_tree();
+    let (sender, _receiver) = loopback::pair(Options::default()).unwrap();
+
+    let sent = start_sender_fsm(&sender, &paths, None, &Target::Named("nobody".into())).await;
+
+    assert!(matches!(sent, SenderState::NoDevicesFound), "sender ended in {:?}", sent);
+}
+
+#[tokio::test]
+async fn declined_offer_stops_both_sides() {
+    let (_src, paths) = source_tree();
+    let out = tempfile::tempdir().unwrap();
+    let options = Options { accept: false, ..Options::default() };
+
+    let Outcome { sent, received, .. } = run(options, &paths, out.path()).await;
+
+    assert!(matches!(sent, SenderState::Declined), "sender ended in {:?}", sent);
+    assert!(matches!(received, ReceiverState::Declined), "receiver ended in {:?}", received);
+    assert_eq!(files_in(out.path()), 0);
+}
+
+#[tokio::test]
+async fn mismatched_code_fails_the_pairing() {
+    let (_src, paths) = source_tree();
+    let out = tempfile::tempdir().unwrap();
+    let options = Options { codes_match: false, ..Options::default() };
+
+    let Outcome { sent, received, .. } = run(options, &paths, out.path()).await;
+
+    assert!(matches!(sent, SenderState::ConnectionFailed), "sender ended in {:?}", sent);
+    assert!(matches!(received, ReceiverState::ConnectionFailed), "receiver ended in {:?}", received);
+    assert_eq!(files_in(out.path()), 0);
+}
+
+#[tokio::test]
+async fn sender_times_out_without_a_receiver() {
+    let (_src, paths) = source_tree();
+    let (sender, _receiver) =
+        loopback::pair(Options { timeout: Duration::from_millis(200), ..Options::default() }).unwrap();
+
+    let sent = start_sender_fsm(&sender, &paths, None, &Target::Ask).await;
+
+    assert!(matches!(sent, SenderState::ConnectionFailed), "sender ended in {:?}", sent);
+}
+
+#[tokio::test]
+async fn corrupted_stream_is_rejected() {
+    let (_src, paths) = source_tree();
+    let out = tempfile::tempdir().unwrap();
+    let options = Options {
+        timeout: Duration::from_secs(1),
+        corrupt_at: Some(64 * 1024),
+        ..Options::default()
+    };
+
+    let Outcome { sent, received, .. } = run(options, &paths, out.path()).await;
+
+    assert!(!matches!(sent, SenderState::SendSuccess), "sender ended in {:?}", sent);
+    assert!(matches!(received, ReceiverState::ReceiveFailed), "receiver ended in {:?}", received);
+}
+
+#[tokio::test]
+async fn several_files_to_stdout_are_refused_with_a_report() {
+    let (_src, paths) = source_tree();
+    let (sender, receiver) = loopback::pair(Options::default()).unwrap();
+
+    let (sent, received) = tokio::join!(
+        start_sender_fsm(&sender, &paths, None, &Target::Ask),
+        start_receiver_fsm(&receiver, Destination::Stdout),
+    );
+
+    // A refusal, not a dropped link the sender would wait to resume.
+    assert!(matches!(sent, SenderState::SendFailed), "sender ended in {:?}", sent);
+    assert!(matches!(received, ReceiverState::ReceiveFailed), "receiver ended in {:?}", received);
+}
+
+#[tokio::test]
+async fn conflicts_are_asked_about_before_anything_is_written() {
+    let (src, paths) = source_tree();
+    let out = tempfile::tempdir().unwrap();
+    fs::create_dir_all(out.path().join("notes")).unwrap();
+    fs::write(out.path().join("notes/a.txt"), "mine\n").unwrap();
+    let (sender, receiver) = loopback::pair(Options::default()).unwrap();
+    let destination = Destination::Dir {
+        path: out.path().to_path_buf(),
+        on_conflict: ConflictPolicy::Ask,
+        ask: |rel| match rel {
+            "notes/a.txt" => Ok(ConflictPolicy::Skip),
+            other => Err(format!("asked about '{}', which does not exist", other)),
+        },
+    };
+
+    let (sent, received) = tokio::join!(
+        start_sender_fsm(&sender, &paths, None, &Target::Ask),
+        start_receiver_fsm(&receiver, destination),
+    );
+
+    assert!(matches!(sent, SenderState::SendSuccess), "sender ended in {:?}", sent);
+    assert!(matches!(received, ReceiverState::ReceiveSuccess), "receiver ended in {:?}", received);
+    assert_eq!(fs::read_to_string(out.path().join("notes/a.txt")).unwrap(), "mine\n");
+    for rel in ["big.bin", "notes/deep/b.txt"] {
+        assert_eq!(fs::read(src.path().join(rel)).unwrap(), fs::read(out.path().join(rel)).unwrap());
+    }
+}
+
+#[tokio::test]
+async fn cut_link_resumes_where_it_left_off() {
+    let (src, paths) = source_tree();
+    let out = tempfile::tempdir().unwrap();
+    let options = Options { cut_at: Some(2 * 1024 * 1024), ..Options::default() };
+
+    let Outcome { sent, received, .. } = run(options, &paths, out.path()).await;
+
+    assert!(matches!(sent, SenderState::SendSuccess), "sender ended in {:?}", sent);
+    assert!(matches!(received, ReceiverState::ReceiveSuccess), "receiver ended in {:?}", received);
+    assert_same_tree(src.path(), out.path());
+}
+
+#[test]
+fn manifest_paths_stay_inside_the_receive_directory() {
+    let dir = tempfile::tempdir().unwrap();
+    let elsewhere = tempfile::tempdir().unwrap();
+    std::os::unix::fs::symlink(elsewhere.path(), dir.path().join("link")).unwrap();
+    let place = |rel| archive::resolve_target(dir.path(), rel, ConflictPolicy::Rename);
+
+    let escaping = ["../escape", "notes/../../escape", "/etc/passwd", "link/file"];
+    let reserved = [".fling-partial", ".fling-partial.tmp"];
+    for rel in escaping.into_iter().chain(reserved) {
+        assert!(matches!(place(rel), archive::Target::Reject(_)), "'{}' was not rejected", rel);
+    }
+    assert_eq!(place("notes/a.txt").path(), Some(dir.path().join("notes/a.txt").as_path()));
+}
+
+#[test]
+fn oversized_manifest_is_refused_as_a_whole() {
+    let entry = |path: String, size| ManifestEntry { path, size, mode: 0o644, sha256: String::new() };
+    // A declared 1 PiB, which the receiver must not try to walk chunk by chunk.
+    let huge = Manifest {
+        entries: vec![entry("huge.bin".into(), 1 << 50)],
+        total_bytes: 1 << 50,
+        streamed: false,
+    };
+    let many = Manifest {
+        entries: (0..=archive::MAX_FILES).map(|i| entry(format!("{}.txt", i), 0)).collect(),
+        total_bytes: 0,
+        streamed: false,
+    };
+
+    for manifest in [&huge, &many] {
+        let dir = tempfile::tempdir().unwrap();
+        assert!(archive::check_limits(manifest).is_err());
+        assert!(Unpacker::open(manifest, dir.path(), |_| ConflictPolicy::Rename).is_err());
+        assert_eq!(files_in(dir.path()), 0);
+    }
+}
+
+#[test]
+fn files_take_no_space_before_their_data_arrives() {
+    let dir = tempfile::tempdir().unwrap();
+    let size = archive::MAX_TOTAL_BYTES / 2;
+    let entry = ManifestEntry { path: "claimed.bin".into(), size, mode: 0o644, sha256: String::new() };
+    let manifest = Manifest { entries: vec![entry], total_bytes: size, streamed: false };
+
+    let unpacker = Unpacker::open(&manifest, dir.path(), |_| ConflictPolicy::Rename).unwrap();
+
+    assert_eq!(unpacker.received_bytes(), 0);
+    assert_eq!(fs::metadata(dir.path().join("claimed.bin")).unwrap().len(), 0);
+}
+
+#[test]
+fn receiver_advert_round_trips_and_checks_the_version() {
+    let advert = ReceiverAdvert { capabilities: gatt::JOINS_WIFI | gatt::USES_LAN, id: "0A:1B:2C:3D:4E:5F".into() };
+    let encoded = advert.encode().unwrap();
+    assert_eq!(ReceiverAdvert::decode(&encoded), Ok(advert));
+
+    let mut newer = encoded.clone();
+    newer[0] = gatt::PROTOCOL_VERSION + 1;
+    assert!(ReceiverAdvert::decode(&newer).is_err());
+    assert!(ReceiverAdvert::decode(&encoded[..4]).is_err());
+}